@@ -1,4 +1,5 @@
 use std::cmp;
+use std::collections::HashMap;
 use std::fmt;
 use evmil::analysis::{EvmState, EvmStack};
 use evmil::analysis::{aw256,ConcreteStack,ConcreteState,EvmMemory,trace,ConcreteMemory,UnknownStorage};
@@ -12,22 +13,30 @@ use evmil::util::{Concretizable,w256};
 
 /// An abstract representation of the EVM at a given point in time.
 /// This includes information known about the stack at this point,
-/// along with the free memory pointer.
+/// along with the free memory pointer, any known memory words and any
+/// known storage slots.
 #[derive(Clone,Debug,PartialEq)]
 pub struct AbstractState {
     // Set of free memory pointers on entry.  If this is empty, then
     // the contents of the free memory pointer is unknown.    
     freemem_ptr: Option<usize>,
+    // Set of memory words with known contents, given as
+    // (offset,value) pairs.  No information is known about any other
+    // word.
+    memory: Vec<(usize,w256)>,
     // Set of stack frames on entry.  No information is known about
     // entries marked `None`
-    stack_frame: Vec<Option<w256>>    
+    stack_frame: Vec<Option<w256>>,
+    // Set of storage slots with known contents, given as (key,value)
+    // pairs.  No information is known about any other slot.
+    storage: Vec<(w256,w256)>
 }
 
 impl AbstractState {
     fn new(state: &State) -> Self {
         let freemem_ptr = Self::extract_fmp(state);
         let stack_frame = Self::extract_stack_frame(state);
-        Self{freemem_ptr,stack_frame}
+        Self{freemem_ptr,memory:Vec::new(),stack_frame,storage:Vec::new()}
     }
     pub fn join_states(states: &[Self]) -> Self {
         let mut r = states[0].clone();
@@ -41,9 +50,32 @@ impl AbstractState {
     pub fn freemem_ptr(&self) -> Option<usize> {
         self.freemem_ptr
     }
+    pub fn memory(&self) -> &[(usize,w256)] {
+        &self.memory
+    }
     pub fn stack(&self) -> &[Option<w256>] {
         &self.stack_frame            
     }
+    pub fn storage(&self) -> &[(w256,w256)] {
+        &self.storage
+    }
+    pub fn set_memory(&mut self, memory: &[(usize,w256)]) {
+        self.memory = memory.to_vec();
+    }
+    pub fn set_storage(&mut self, storage: &[(w256,w256)]) {
+        self.storage = storage.to_vec();
+    }
+    pub fn set_stack_item(&mut self, index: usize, value: w256) {
+        if index < self.stack_frame.len() {
+            self.stack_frame[index] = Some(value);
+        }
+    }
+    pub fn clear_freemem_ptr(&mut self) {
+        self.freemem_ptr = None;
+    }
+    pub fn clear_memory(&mut self) {
+        self.memory.clear();
+    }
     pub fn clear_stack_item(&mut self, index: usize) {
         if index < self.stack_frame.len() {
             self.stack_frame[index] = None;
@@ -70,8 +102,12 @@ impl AbstractState {
     pub fn join(&mut self, other: &AbstractState) {
         // Join freemem pointer
         Self::join_word(&mut self.freemem_ptr,&other.freemem_ptr);
+        // Keep only memory words known in both
+        self.memory.retain(|e| other.memory.contains(e));
         //
         self.join_stack(&other.stack_frame);
+        // Keep only storage slots known in both
+        self.storage.retain(|e| other.storage.contains(e));
     }
     /// Remove what is known from one stack.
     pub fn cancel(&mut self, other: &AbstractState) {
@@ -163,7 +199,10 @@ pub struct BytecodeAnalysis {
     /// Stores information for each instruction.  Observe that this
     /// maps _byte offsets_ to analysis results (i.e. not _instruction
     /// offsets_).
-    states: Vec<Vec<AbstractState>>
+    states: Vec<Vec<AbstractState>>,
+    /// Stores the targets of each branch, indexed by _instruction
+    /// offset_.  This is empty for any other instruction.
+    targets: Vec<Vec<usize>>
 }
 
 impl BytecodeAnalysis {
@@ -177,13 +216,28 @@ impl BytecodeAnalysis {
 	let mut err = false;
         let trace : Vec<Vec<State>> = trace(&insns,init,limit).map_err(|_| ())?;
         // Convert into abstract states
-        for t in trace {
+        for t in &trace {
             let mut s:Vec<_> = t.iter().map(|s| AbstractState::new(s)).collect();
             s.dedup();
             states.push(s);
         }
+        // Recover constants not folded by the trace itself
+        fold_constants(insns,&mut states);
+        fold_storage(insns,&mut states);
+        fold_memory(insns,&mut states);
+        fold_freemem_ptr(insns,&mut states);
+        // Determine branch targets
+        let mut targets = Vec::new();
+        let mut pc = 0;
+        for (i,insn) in insns.iter().enumerate() {
+            match insn {
+                RJUMP(_)|RJUMPI(_)|JUMP|JUMPI => targets.push(branch_targets(pc,i,insn,&trace)),
+                _ => targets.push(Vec::new())
+            }
+            pc += insn.length();
+        }
         //
-        Ok(Self{states})
+        Ok(Self{states,targets})
     }
 
     /// Get the set of abstract states at a given instruction within
@@ -192,8 +246,314 @@ impl BytecodeAnalysis {
     pub fn get_states(&self, index: usize) -> &[AbstractState] {
         &self.states[index]
     }
+
+    /// Get the targets of the branch at a given instruction within
+    /// the original sequence (i.e. an _instruction offset_ rather
+    /// than a _byte offset_).
+    pub fn get_targets(&self, index: usize) -> &[usize] {
+        &self.targets[index]
+    }
+}
+
+
+// =============================================================================
+// Constant Folding
+// =============================================================================
+
+/// Fold `SIGNEXTEND`, comparison and bitwise instructions whose
+/// operands are known constants in every state.  The result is then
+/// propagated forwards through the straight-line code which follows,
+/// until either it is consumed or a join point (i.e. `JUMPDEST`) is
+/// reached.
+fn fold_constants(insns: &[Instruction], states: &mut [Vec<AbstractState>]) {
+    for i in 0..insns.len() {
+        if !matches!(insns[i],SIGNEXTEND|LT|GT|EQ|ISZERO|AND|OR|XOR) || states[i].len() == 0 { continue; }
+        // Determine result (if constant across all states)
+        let mut result = None;
+        for s in &states[i] {
+            let r = evaluate(&insns[i],s.stack());
+            if r.is_none() || (result.is_some() && result != r) {
+                result = None;
+                break;
+            }
+            result = r;
+        }
+        let result = match result { Some(r) => r, None => { continue; } };
+        propagate_constant(insns,states,i,result);
+    }
+}
+
+/// Track storage slots written with constant values by `SSTORE`, and
+/// fold subsequent `SLOAD` instructions from those slots.  Since calls
+/// and contract creations may reenter this contract, nothing is known
+/// about any slot after them.
+fn fold_storage(insns: &[Instruction], states: &mut [Vec<AbstractState>]) {
+    let known = forward_facts(insns,states,|insn,sts,slots: &mut Vec<(w256,w256)>| {
+        match insn {
+            SSTORE => {
+                let key = constant_operand(sts,0);
+                let val = constant_operand(sts,1);
+                match key {
+                    Some(k) => {
+                        slots.retain(|(l,_)| *l != k);
+                        if let Some(v) = val { slots.push((k,v)); }
+                    }
+                    None => { slots.clear(); }
+                }
+            }
+            CALL|CALLCODE|DELEGATECALL|CREATE|CREATE2 => { slots.clear(); }
+            _ => {}
+        }
+    });
+    // Record known slots, and fold loads from them
+    for (i,slots) in known.into_iter().enumerate() {
+        for s in &mut states[i] { s.set_storage(&slots); }
+        if matches!(insns[i],SLOAD) {
+            let key = constant_operand(&states[i],0);
+            let val = slots.iter().find(|(l,_)| Some(*l) == key).map(|(_,v)| *v);
+            if let Some(v) = val { propagate_constant(insns,states,i,v); }
+        }
+    }
+}
+
+/// Track memory words written with constant values by `MSTORE` to a
+/// constant offset.  Any write which may overlap a word means nothing
+/// is known about it thereafter.  Observe that the free memory pointer
+/// (i.e. `0x40`) is tracked separately.
+fn fold_memory(insns: &[Instruction], states: &mut [Vec<AbstractState>]) {
+    let known = forward_facts(insns,states,|insn,sts,words: &mut Vec<(usize,w256)>| {
+        let addr = constant_operand(sts,0).filter(|a| a.byte_len() <= 4).map(|a| a.to::<usize>());
+        match (insn,addr) {
+            (MSTORE|MSTORE8,Some(a)) => {
+                let width = if matches!(insn,MSTORE) { 32 } else { 1 };
+                words.retain(|(o,_)| a + width <= *o || *o + 32 <= a);
+                if let (MSTORE,Some(v)) = (insn,constant_operand(sts,1)) {
+                    if a != 0x40 { words.push((a,v)); }
+                }
+            }
+            (MSTORE|MSTORE8,None) => { words.clear(); }
+            (CALLDATACOPY|CODECOPY|RETURNDATACOPY|EXTCODECOPY|MCOPY,_) => { words.clear(); }
+            (CALL|CALLCODE|DELEGATECALL|STATICCALL,_) => { words.clear(); }
+            _ => {}
+        }
+    });
+    for (i,words) in known.into_iter().enumerate() {
+        for s in &mut states[i] { s.set_memory(&words); }
+    }
+}
+
+/// Determine the facts known on entry to each instruction, using a
+/// forwards dataflow analysis where a given function applies the
+/// effect of each instruction.  The facts known at a join point
+/// (e.g. `JUMPDEST`) are those known along every incoming edge, and
+/// nothing is known on entry to an unreached instruction.
+fn forward_facts<T,F>(insns: &[Instruction], states: &[Vec<AbstractState>], transfer: F) -> Vec<Vec<T>>
+where T: Clone+PartialEq, F: Fn(&Instruction,&[AbstractState],&mut Vec<T>) {
+    // Determine byte offset of each instruction
+    let mut pcs = Vec::new();
+    let mut pc = 0;
+    for insn in insns {
+        pcs.push(pc);
+        pc += insn.length();
+    }
+    let offsets : HashMap<usize,usize> = pcs.iter().enumerate().map(|(i,pc)| (*pc,i)).collect();
+    // Facts known on entry to each instruction (or `None` if unreached)
+    let mut known : Vec<Option<Vec<T>>> = vec![None; insns.len()];
+    let mut worklist = Vec::new();
+    if insns.len() > 0 {
+        known[0] = Some(Vec::new());
+        worklist.push(0);
+    }
+    // An unresolved jump could reach any join point
+    let unresolved = (0..insns.len()).any(|i| {
+        matches!(insns[i],JUMP|JUMPI) && states[i].iter().any(|s| matches!(s.stack().get(0),Some(None)))
+    });
+    if unresolved {
+        for i in 0..insns.len() {
+            if matches!(insns[i],JUMPDEST) {
+                known[i] = Some(Vec::new());
+                worklist.push(i);
+            }
+        }
+    }
+    // Propagate to a fixed point
+    while let Some(i) = worklist.pop() {
+        let mut facts = known[i].clone().unwrap();
+        transfer(&insns[i],&states[i],&mut facts);
+        // Determine successors
+        let mut succs = Vec::new();
+        match &insns[i] {
+            JUMP|JUMPI => {
+                for s in &states[i] {
+                    match s.stack().get(0) {
+                        Some(Some(t)) if t.byte_len() <= 8 => succs.extend(offsets.get(&t.to::<usize>())),
+                        _ => {}
+                    }
+                }
+            }
+            RJUMP(offset)|RJUMPI(offset) => {
+                let target = ((pcs[i] + insns[i].length()) as isize) + (*offset as isize);
+                succs.extend(offsets.get(&(target as usize)));
+            }
+            _ => {}
+        }
+        if insns[i].fallthru() && i + 1 < insns.len() { succs.push(i + 1); }
+        //
+        for j in succs {
+            if join_facts(&mut known[j],&facts) { worklist.push(j); }
+        }
+    }
+    known.into_iter().map(|k| k.unwrap_or_default()).collect()
+}
+
+/// Join the facts known along some edge into those known on entry to
+/// an instruction, returning `true` if the latter changed.
+fn join_facts<T:Clone+PartialEq>(known: &mut Option<Vec<T>>, facts: &[T]) -> bool {
+    match known {
+        None => {
+            *known = Some(facts.to_vec());
+            true
+        }
+        Some(k) => {
+            let n = k.len();
+            k.retain(|e| facts.contains(e));
+            k.len() != n
+        }
+    }
+}
+
+/// Track constant values written to the free memory pointer (i.e. by
+/// `MSTORE` to `0x40`) which the trace itself loses, as happens when
+/// the pointer is advanced by a constant.  The value is propagated
+/// forwards through the straight-line code which follows, until
+/// either the pointer may be overwritten or a join point (i.e.
+/// `JUMPDEST`) is reached.
+fn fold_freemem_ptr(insns: &[Instruction], states: &mut [Vec<AbstractState>]) {
+    let fmp = w256::from(0x40);
+    let mut known : Option<usize> = None;
+    for i in 0..insns.len() {
+        if matches!(insns[i],JUMPDEST) { known = None; }
+        if let Some(v) = known {
+            for s in &mut states[i] { s.freemem_ptr = Some(v); }
+        }
+        let addr = constant_operand(&states[i],0);
+        match insns[i] {
+            MSTORE if addr == Some(fmp) => {
+                let val = constant_operand(&states[i],1);
+                known = val.filter(|v| v.byte_len() <= 8).map(|v| v.to());
+            }
+            MSTORE|MSTORE8 => {
+                let width = if matches!(insns[i],MSTORE) { 32 } else { 1 };
+                // Check whether write may overlap the pointer
+                let disjoint = match addr {
+                    Some(a) if a.byte_len() <= 8 => {
+                        let a = a.to::<usize>();
+                        a.saturating_add(width) <= 0x40 || a >= 0x60
+                    }
+                    _ => false
+                };
+                if !disjoint { known = None; }
+            }
+            CALLDATACOPY|CODECOPY|RETURNDATACOPY|EXTCODECOPY|MCOPY => { known = None; }
+            CALL|CALLCODE|DELEGATECALL|STATICCALL => { known = None; }
+            _ => {}
+        }
+        if !insns[i].fallthru() { known = None; }
+    }
+}
+
+/// Determine the value of a given stack operand, provided it is the
+/// same constant across all states.
+pub fn constant_operand(states: &[AbstractState], index: usize) -> Option<w256> {
+    let mut result = None;
+    for s in states {
+        let r = match s.stack().get(index) { Some(Some(v)) => Some(*v), _ => None };
+        if r.is_none() || (result.is_some() && result != r) { return None; }
+        result = r;
+    }
+    result
+}
+
+/// Propagate a constant produced by the instruction at a given index
+/// forwards through the straight-line code which follows, until
+/// either it is consumed or a join point (i.e. `JUMPDEST`) is reached.
+fn propagate_constant(insns: &[Instruction], states: &mut [Vec<AbstractState>], i: usize, result: w256) {
+    let mut positions = vec![0];
+    let mut j = i + 1;
+    while j < insns.len() && positions.len() > 0 && !matches!(insns[j],JUMPDEST) {
+        for s in &mut states[j] {
+            for p in &positions { s.set_stack_item(*p,result); }
+        }
+        if !insns[j].fallthru() { break; }
+        positions = shift_positions(&insns[j],positions);
+        j += 1;
+    }
+}
+
+/// Determine where items at the given stack positions end up after
+/// executing a given instruction.
+fn shift_positions(insn: &Instruction, positions: Vec<usize>) -> Vec<usize> {
+    let mut npositions = Vec::new();
+    match insn {
+        DUP(n) => {
+            let n = *n as usize;
+            for p in positions {
+                if p == n - 1 { npositions.push(0); }
+                npositions.push(p + 1);
+            }
+        }
+        SWAP(n) => {
+            let n = *n as usize;
+            for p in positions {
+                if p == 0 { npositions.push(n); }
+                else if p == n { npositions.push(0); }
+                else { npositions.push(p); }
+            }
+        }
+        _ if !crate::block::is_supported(insn) => {
+            // Nothing is known after an unsupported instruction
+        }
+        _ => {
+            let m = insn.operands();
+            let k = crate::block::insn_produces(insn);
+            for p in positions {
+                // Items consumed by the instruction are lost
+                if p >= m { npositions.push(p - m + k); }
+            }
+        }
+    }
+    npositions
+}
+
+/// Evaluate a given instruction against a given stack, provided its
+/// operands are known constants.
+fn evaluate(insn: &Instruction, stack: &[Option<w256>]) -> Option<w256> {
+    let flag = |b: bool| if b { w256::from(1) } else { w256::from(0) };
+    let x = stack.get(0).copied().flatten()?;
+    if matches!(insn,ISZERO) { return Some(flag(x == w256::from(0))); }
+    let y = stack.get(1).copied().flatten()?;
+    let r = match insn {
+        SIGNEXTEND => signextend(x,y),
+        LT => flag(x < y),
+        GT => flag(x > y),
+        EQ => flag(x == y),
+        AND => x & y,
+        OR => x | y,
+        XOR => x ^ y,
+        _ => { return None; }
+    };
+    Some(r)
 }
 
+/// Sign extend a given value `x` from a given number of bytes `b + 1`,
+/// following the semantics of the `SIGNEXTEND` instruction.  When `b
+/// >= 31` the value is unchanged.
+pub fn signextend(b: w256, x: w256) -> w256 {
+    if b >= w256::from(31) { return x; }
+    let bit = (8 * b.to::<usize>()) + 7;
+    let mask = (w256::from(1) << (bit + 1)) - w256::from(1);
+    if x.bit(bit) { x | !mask } else { x & mask }
+}
 
 // =============================================================================
 // Helpers
@@ -202,18 +562,25 @@ impl BytecodeAnalysis {
 // Package up a suitable state for the analysis
 pub type State = ConcreteState<ConcreteStack<aw256>,ConcreteMemory<aw256>,UnknownStorage<aw256>>;
 
-pub fn determine_stack_size(index: usize, analysis: &[Vec<State>]) -> (usize,usize) {
+/// Determine the minimum and maximum stack sizes at a given
+/// instruction offset, or `None` if the instruction was never reached.
+pub fn determine_stack_size(index: usize, analysis: &[Vec<State>]) -> Option<(usize,usize)> {
     let mut min = usize::MAX;
     let mut max = 0;
+    //
+    if analysis[index].len() == 0 { return None; }
     // 
     for s in analysis[index].iter() {    
         min = min.min(s.stack().size());
         max = max.max(s.stack().size());        
     }
     //
-    (min,max)
+    Some((min,max))
 }    
 
+/// Determine the set of (distinct) values for the ith stack item at a
+/// given instruction offset.  This returns `None` if the value is
+/// unknown in some state.
 pub fn extract_stack_values(i: usize, index: usize, analysis: &[Vec<State>]) -> Option<Vec<w256>> {
     let mut values = Vec::new();
     // 
@@ -236,6 +603,9 @@ pub fn extract_stack_values(i: usize, index: usize, analysis: &[Vec<State>]) ->
     Some(values)
 }    
 
+/// Determine the set of (distinct) values for the free memory pointer
+/// at a given instruction offset.  This is empty if the value is
+/// unknown in some state.
 pub fn extract_free_mem_pointer(index: usize, analysis: &[Vec<State>]) -> Vec<usize> {
     let fmp = aw256::from(w256::from(0x40));
     let mut values = Vec::new();
@@ -262,8 +632,11 @@ pub fn extract_free_mem_pointer(index: usize, analysis: &[Vec<State>]) -> Vec<us
     values
 }
 
-// Determine the target of this branch
-pub fn branch_targets(mut pc: usize, insn: &Instruction, analysis: &[Vec<State>]) -> Vec<usize> {    
+/// Determine the targets of a given branch at a given byte offset
+/// (`pc`) and instruction offset (`index`).  Relative jumps are
+/// resolved statically, whilst other jumps are resolved using the
+/// (known) values on top of the stack.
+pub fn branch_targets(mut pc: usize, index: usize, insn: &Instruction, analysis: &[Vec<State>]) -> Vec<usize> {    
     match insn {
         RJUMP(offset)|RJUMPI(offset) => {
             // Push pc to past this instruction
@@ -275,9 +648,12 @@ pub fn branch_targets(mut pc: usize, insn: &Instruction, analysis: &[Vec<State>]
         }
 	JUMP|JUMPI => {
 	    let mut targets : Vec<usize> = Vec::new();
-	    for s in analysis[pc].iter() {
-                let target = s.stack().peek(0).constant();
-		targets.push(target.to());
+	    for s in analysis[index].iter() {
+                let target = s.stack().peek(0);
+                // Unknown targets cannot be resolved
+                if target.is_constant() {
+		    targets.push(target.constant().to());
+                }
 	    }
             targets.sort_unstable();
 	    targets.dedup();
@@ -289,3 +665,143 @@ pub fn branch_targets(mut pc: usize, insn: &Instruction, analysis: &[Vec<State>]
     }
 }
 
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the abstract trace over a given instruction sequence.
+    fn analyse(insns: &[Instruction]) -> Vec<Vec<State>> {
+        trace(insns,State::new(),usize::MAX).unwrap()
+    }
+
+    /// Construct a sequence where two paths join at a `JUMPDEST`
+    /// (index 10) with either `1` or a given value on the stack.
+    fn join(value: u8) -> Vec<Instruction> {
+        vec![PUSH(vec![0x00]),CALLDATALOAD,PUSH(vec![0x0c]),JUMPI,
+             PUSH(vec![0x01]),PUSH(vec![0x0f]),JUMP,STOP,
+             JUMPDEST,PUSH(vec![value]),JUMPDEST,STOP]
+    }
+
+    #[test]
+    fn test_stack_size_01() {
+        let t = analyse(&[PUSH(vec![0x01]),PUSH(vec![0x02]),ADD,STOP]);
+        assert_eq!(determine_stack_size(2,&t),Some((2,2)));
+        assert_eq!(determine_stack_size(3,&t),Some((1,1)));
+    }
+
+    #[test]
+    fn test_stack_values_01() {
+        // Same value on both paths
+        let t = analyse(&join(0x01));
+        assert_eq!(extract_stack_values(0,10,&t),Some(vec![w256::from(1)]));
+    }
+
+    #[test]
+    fn test_stack_values_02() {
+        // Distinct values on each path
+        let t = analyse(&join(0x02));
+        assert_eq!(extract_stack_values(0,10,&t),Some(vec![w256::from(1),w256::from(2)]));
+    }
+
+    #[test]
+    fn test_stack_values_03() {
+        // Value loaded from calldata is unknown
+        let t = analyse(&join(0x01));
+        assert_eq!(extract_stack_values(0,2,&t),Some(vec![w256::from(0)]));
+        assert_eq!(extract_stack_values(0,3,&t),None);
+    }
+
+    #[test]
+    fn test_free_mem_pointer_01() {
+        let t = analyse(&[PUSH(vec![0x80]),PUSH(vec![0x40]),MSTORE,STOP]);
+        assert_eq!(extract_free_mem_pointer(3,&t),vec![0x80]);
+    }
+
+    #[test]
+    fn test_branch_targets_01() {
+        // Relative jumps are resolved statically
+        assert_eq!(branch_targets(0x10,0,&RJUMP(3),&[]),vec![0x16]);
+        assert_eq!(branch_targets(0x10,0,&RJUMPI(-3),&[]),vec![0x10]);
+    }
+
+    #[test]
+    fn test_branch_targets_02() {
+        // Other jumps are resolved from the stack
+        let insns = join(0x01);
+        let t = analyse(&insns);
+        assert_eq!(branch_targets(0x05,3,&JUMPI,&t),vec![0x0c]);
+        assert_eq!(branch_targets(0x0a,6,&JUMP,&t),vec![0x0f]);
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_targets(3),&[0x0c]);
+        assert!(analysis.get_targets(0).is_empty());
+    }
+
+    #[test]
+    fn test_fold_storage_01() {
+        // Slot known across a join point
+        let insns = vec![PUSH(vec![0x2a]),PUSH(vec![0x1]),SSTORE,PUSH(vec![0x8]),JUMP,
+                         JUMPDEST,PUSH(vec![0x1]),SLOAD,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(7)[0].storage(),&[(w256::from(1),w256::from(0x2a))]);
+        assert_eq!(analysis.get_states(8)[0].stack()[0],Some(w256::from(0x2a)));
+    }
+
+    #[test]
+    fn test_fold_storage_02() {
+        // Slot unknown after a call
+        let mut insns = vec![PUSH(vec![0x2a]),PUSH(vec![0x1]),SSTORE];
+        insns.extend(vec![PUSH(vec![0x0]); 7]);
+        insns.extend(vec![CALL,POP,PUSH(vec![0x1]),SLOAD,STOP]);
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert!(analysis.get_states(13)[0].storage().is_empty());
+        assert_eq!(analysis.get_states(14)[0].stack()[0],None);
+    }
+
+    #[test]
+    fn test_fold_memory_01() {
+        // Words known across a join point, including zeros
+        let insns = vec![PUSH(vec![0x2a]),PUSH(vec![0x0]),MSTORE,PUSH(vec![0x0]),PUSH(vec![0x80]),MSTORE,
+                         PUSH(vec![0xd]),JUMP,JUMPDEST,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(9)[0].memory(),&[(0x0,w256::from(0x2a)),(0x80,w256::from(0))]);
+    }
+
+    #[test]
+    fn test_fold_memory_02() {
+        // Word unknown after an overlapping write
+        let insns = vec![PUSH(vec![0x2a]),PUSH(vec![0x0]),MSTORE,PUSH(vec![0x1]),PUSH(vec![0x1f]),MSTORE8,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(5)[0].memory(),&[(0x0,w256::from(0x2a))]);
+        assert!(analysis.get_states(6)[0].memory().is_empty());
+    }
+
+    #[test]
+    fn test_fold_constants_01() {
+        // Fully constant EQ yields a known boolean
+        let insns = vec![PUSH(vec![0x2a]),PUSH(vec![0x2a]),EQ,PUSH(vec![0x2a]),PUSH(vec![0x1]),EQ,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(3)[0].stack()[0],Some(w256::from(1)));
+        assert_eq!(analysis.get_states(6)[0].stack()[0],Some(w256::from(0)));
+        assert_eq!(analysis.get_states(6)[0].stack()[1],Some(w256::from(1)));
+    }
+
+    #[test]
+    fn test_fold_constants_02() {
+        // EQ against an unknown value is not folded
+        let insns = vec![PUSH(vec![0x0]),CALLDATALOAD,PUSH(vec![0x1]),EQ,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(4)[0].stack()[0],None);
+    }
+
+    #[test]
+    fn test_fold_freemem_ptr_overflow_01() {
+        // Write to a huge offset leaves the pointer intact
+        let insns = vec![PUSH(vec![0x80]),PUSH(vec![0x40]),MSTORE,PUSH(vec![0x1]),PUSH(vec![0xff;8]),MSTORE,STOP];
+        let analysis = BytecodeAnalysis::from_insns(&insns,usize::MAX).unwrap();
+        assert_eq!(analysis.get_states(6)[0].freemem_ptr(),Some(0x80));
+    }
+}
@@ -1,18 +1,39 @@
 use std::collections::HashMap;
+use std::fmt;
 use evmil::bytecode::Instruction;
 use evmil::bytecode::Instruction::*;
 use evmil::util::w256;
 use crate::analysis::{BytecodeAnalysis,AbstractState};
 use crate::opcodes::OPCODES;
+use crate::refine::{Fact,StackValue,StackValues,determine_stack_values,refine_branches};
 
 #[derive(Clone,Debug)]
 pub enum Bytecode {
     Comment(String),
+    Havoc(usize),
     Assert(Vec<usize>,String),
     Mask(usize),
     Unit(Instruction),
     JumpI(Vec<usize>),
-    Jump(Vec<usize>)
+    Jump(Vec<usize>),
+    /// Relative (conditional) jump, as found in EOF code sections.
+    RJumpI(Vec<usize>),
+    /// Relative (unconditional) jump, as found in EOF code sections.
+    RJump(Vec<usize>)
+}
+
+impl Bytecode {
+    /// Determine the number of bytes this bytecode occupies in the
+    /// original instruction sequence.  Comments, havocs and assertions
+    /// correspond to no bytes.
+    pub fn length(&self) -> usize {
+        match self {
+            Bytecode::Comment(_)|Bytecode::Havoc(_)|Bytecode::Assert(_,_) => 0,
+            Bytecode::Mask(_)|Bytecode::JumpI(_)|Bytecode::Jump(_) => 1,
+            Bytecode::RJumpI(_)|Bytecode::RJump(_) => 3,
+            Bytecode::Unit(insn) => insn.length()
+        }
+    }
 }
 
 /// Represents a basic block within a given sequence of instructions.
@@ -27,7 +48,61 @@ pub struct Block {
     // The set of bytecodes
     bytecodes: Vec<Bytecode>,
     // Fall-thru (if applicable)
-    next: Option<usize>
+    next: Option<usize>,
+    // Facts known on entry from the (unique) incoming branch.
+    facts: Vec<Fact>,
+    // Reason this block is unreachable (if applicable)
+    dead: Option<Unreachable>,
+    // Known properties of stack items on entry.
+    values: StackValues,
+    // Names of the functions which own this block, and whether they
+    // share it (i.e. reach it without dominating it).
+    owners: Vec<String>,
+    shared: bool,
+    // Signals no call can have been made prior to entering this block.
+    call_free: bool,
+    // Signals this block is the target of a back-edge.
+    loop_header: bool,
+    /// Signals whether this block participates in a loop (i.e. lies on
+    /// some cycle).
+    in_loop: bool,
+    // Jump targets of this block known to begin with a `JUMPDEST`.
+    jumpdests: Vec<usize>,
+    // Signals this block (or some block it reaches) may modify state,
+    // and hence requires writes to be permitted.
+    may_write: bool,
+    // Offsets of identical blocks which share this block's method.
+    aliases: Vec<usize>,
+    // Successors of this block whose method is shared with another
+    // (identical) block, given as pairs `(from,to)`.
+    redirects: Vec<(usize,usize)>,
+    // Offset of the first bytecode which underflows the stack on some
+    // reachable path (if applicable).
+    underflow: Option<usize>
+}
+
+/// Identifies why a given block was determined to be unreachable.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Unreachable {
+    /// No other block transfers control to this block.
+    NoIncomingEdges,
+    /// Every block transferring control to this block is itself
+    /// unreachable.
+    DeadPredecessors,
+    /// Some reachable block has an edge to this block, but that edge
+    /// is never taken under the abstract states (e.g. it is guarded by
+    /// a condition which is always false).
+    EdgeNeverTaken
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unreachable::NoIncomingEdges => write!(f,"no predecessor jumps here"),
+            Unreachable::DeadPredecessors => write!(f,"only reached from deadcode"),
+            Unreachable::EdgeNeverTaken => write!(f,"guarded by always-false condition")
+        }
+    }
 }
 
 impl Block {
@@ -47,13 +122,21 @@ impl Block {
         &self.bytecodes
     }
     // Determine stack bounds on entry to this block.
-    pub fn stack_bounds(&self) -> (usize,usize) {
+    pub fn stack_bounds(&self) -> Option<(usize,usize)> {
         self.states[0].stack_bounds()
     }
     // Determine stack heights on entry to this block.
     pub fn stack_heights(&self) -> Vec<usize> {
         self.states[0].stack_heights()
     }
+    // Determine known memory words on entry to this block.
+    pub fn memory_words(&self) -> Vec<(usize,w256)> {
+        self.states[0].memory_words()
+    }
+    // Determine known storage slots on entry to this block.
+    pub fn storage_slots(&self) -> Vec<(w256,w256)> {
+        self.states[0].storage_slots()
+    }
     // Determine freememory pointer bounds on entry to this block.
     pub fn freemem_ptrs(&self) -> Option<(usize,usize)> {
         self.states[0].freemem_ptr_bounds()
@@ -64,7 +147,118 @@ impl Block {
 	// Block is unreachable if it contains no initial states.
 	self.states[0].states.len() == 0
     }
+    /// Get the known properties of stack items on entry to this block.
+    pub fn stack_values(&self) -> &[StackValue] {
+        &self.values
+    }
+    pub fn set_stack_values(&mut self, values: StackValues) {
+        self.values = values;
+    }
+    /// Check whether no call can have been made before entering this
+    /// block (i.e. there is no pending return data).
+    pub fn is_call_free(&self) -> bool {
+        self.call_free
+    }
+    /// Check whether this block is a loop header (i.e. the target of
+    /// a back-edge).
+    pub fn is_loop_header(&self) -> bool {
+        self.loop_header
+    }
+    pub fn set_loop_header(&mut self, flag: bool) {
+        self.loop_header = flag;
+    }
+
+    pub fn is_in_loop(&self) -> bool {
+        self.in_loop
+    }
+
+    pub fn set_in_loop(&mut self, flag: bool) {
+        self.in_loop = flag;
+    }
+    /// Get the offsets of identical blocks which share this block's
+    /// method.
+    pub fn aliases(&self) -> &[usize] {
+        &self.aliases
+    }
+    /// Determine the block whose method implements a given successor
+    /// of this block.  This differs from the successor itself only
+    /// when it was found identical to another block.
+    pub fn callee(&self, pc: usize) -> usize {
+        match self.redirects.iter().find(|(f,_)| *f == pc) {
+            Some((_,t)) => *t,
+            None => pc
+        }
+    }
+    /// Check whether this block (or some block it can reach) may
+    /// modify state (e.g. via `SSTORE` or `LOG`).
+    pub fn may_write(&self) -> bool {
+        self.may_write
+    }
+    /// Get the byte offset of the first bytecode in this block which
+    /// underflows the stack on some reachable path (if any).
+    pub fn stack_underflow(&self) -> Option<usize> {
+        self.underflow
+    }
+    /// Check whether this block begins with a `JUMPDEST` instruction
+    /// (i.e. is a valid jump destination).
+    pub fn is_jumpdest(&self) -> bool {
+        matches!(self.bytecodes.iter().find(|bc| !matches!(bc,Bytecode::Comment(_)|Bytecode::Havoc(_)|Bytecode::Assert(_,_))),Some(Bytecode::Unit(JUMPDEST)))
+    }
+    /// Get the jump targets of this block which are known to begin
+    /// with a `JUMPDEST` instruction.
+    pub fn jumpdest_targets(&self) -> &[usize] {
+        &self.jumpdests
+    }
+    /// Get the names of the functions owning this block, and whether
+    /// or not this block is shared between them.
+    pub fn owners(&self) -> (&[String],bool) {
+        (&self.owners,self.shared)
+    }
+    pub fn set_owners(&mut self, owners: Vec<String>, shared: bool) {
+        self.owners = owners;
+        self.shared = shared;
+    }
+    /// Determine why this block is unreachable (if it is).
+    pub fn unreachable_reason(&self) -> Option<Unreachable> {
+        self.dead
+    }
     pub fn next(&self) -> Option<usize> { self.next }
+    /// Get the facts known to hold on entry to this block.
+    pub fn facts(&self) -> &[Fact] {
+        &self.facts
+    }
+    pub fn set_facts(&mut self, facts: Vec<Fact>) {
+        self.facts = facts;
+    }
+    /// Determine the code sections whose entry blocks this block calls
+    /// (i.e. via `CALLF`).
+    pub fn callf_targets(&self) -> Vec<usize> {
+        let mut cids : Vec<usize> = self.bytecodes.iter().filter_map(|bc| match bc {
+            Bytecode::Unit(CALLF(c)) => Some(*c as usize),
+            _ => None
+        }).collect();
+        cids.sort_unstable();
+        cids.dedup();
+        cids
+    }
+    /// Determine the set of blocks which this block can transfer
+    /// control to (i.e. via a jump or by falling through).
+    pub fn successors(&self) -> Vec<usize> {
+        let mut succs = Vec::new();
+        for bc in &self.bytecodes {
+            match bc {
+                Bytecode::Jump(targets)|Bytecode::JumpI(targets)|Bytecode::RJump(targets)|Bytecode::RJumpI(targets) => {
+                    succs.extend(targets);
+                }
+                _ => {}
+            }
+        }
+        if let Some(pc) = self.next { succs.push(pc); }
+        for pc in &mut succs { *pc = self.callee(*pc); }
+        succs.sort_unstable();
+        succs.dedup();
+        succs
+    }
 
     pub fn iter(&self) -> std::slice::Iter<Bytecode> {
         self.bytecodes.iter()
@@ -73,6 +267,84 @@ impl Block {
     pub fn entry_state(&self) -> AbstractState {
         self.states[0].join_states()
     }
+    /// Check whether verifying this block needs the concrete contract
+    /// code (e.g. to show jump targets are valid, or to read code).
+    pub fn needs_code(&self) -> bool {
+        self.bytecodes.iter().any(|bc| {
+            matches!(bc,Bytecode::Jump(_)|Bytecode::JumpI(_)|Bytecode::Unit(CODECOPY|CODESIZE))
+        })
+    }
+    /// Determine the net change in stack height between entry to this
+    /// block and its exit.  For blocks which branch, this is the
+    /// change along the fall-thru path.
+    pub fn stack_delta(&self) -> isize {
+        self.bytecodes.iter().map(|bc| {
+            let (n,m) = stack_effect(bc);
+            (m as isize) - (n as isize)
+        }).sum()
+    }
+    /// Determine the minimum number of operands which must be present
+    /// on entry to this block for it to execute without underflow.
+    /// This accounts for instructions reaching deep into the stack,
+    /// such as `DUP16` or `SWAP16`.
+    pub fn required_operands(&self) -> usize {
+        let mut delta = 0;
+        let mut required = 0;
+        for bc in &self.bytecodes {
+            let (n,m) = stack_effect(bc);
+            required = required.max((n as isize) - delta);
+            delta += (m as isize) - (n as isize);
+        }
+        required as usize
+    }
+    /// Determine the value of the ith operand for the jth bytecode in
+    /// this block, provided it is a known (and reasonably sized)
+    /// constant.
+    pub fn constant_operand(&self, j: usize, i: usize) -> Option<usize> {
+        let states = self.states[j].states();
+        if states.len() == 0 { return None; }
+        match operand(i,states) {
+            Some(v) if v.byte_len() <= 8 => Some(v.to()),
+            _ => None
+        }
+    }
+    /// Check whether the jth bytecode in this block reads the free
+    /// memory pointer (i.e. is an `MLOAD` from `0x40`).
+    pub fn is_fmp_read(&self, j: usize) -> bool {
+        matches!(self.bytecodes[j],Bytecode::Unit(MLOAD)) && self.constant_operand(j,0) == Some(0x40)
+    }
+    /// Determine how many times this block reads the free memory
+    /// pointer.
+    pub fn fmp_reads(&self) -> usize {
+        (0..self.bytecodes.len()).filter(|j| self.is_fmp_read(*j)).count()
+    }
+    /// Check whether this block may write to the free memory pointer
+    /// (i.e. memory location `0x40`).  This is conservative, in that
+    /// any write to an unknown location is assumed to do so.
+    pub fn may_write_fmp(&self) -> bool {
+        (0..self.bytecodes.len()).any(|j| self.writes_fmp(j))
+    }
+    /// Check whether the jth bytecode in this block may write to the
+    /// free memory pointer.  As above, this is conservative.
+    pub fn writes_fmp(&self, j: usize) -> bool {
+        let width = match self.bytecodes[j] {
+            Bytecode::Unit(MSTORE) => 32,
+            Bytecode::Unit(MSTORE8) => 1,
+            Bytecode::Unit(CALLDATACOPY|CODECOPY|RETURNDATACOPY|EXTCODECOPY|MCOPY) => { return true; }
+            Bytecode::Unit(CALL|CALLCODE|DELEGATECALL|STATICCALL) => { return true; }
+            _ => { return false; }
+        };
+        match self.constant_operand(j,0) {
+            Some(off) => off.saturating_add(width) > 0x40 && off < 0x60,
+            None => true
+        }
+    }
+    /// Check whether the jth bytecode in this block definitely
+    /// overwrites the free memory pointer (i.e. is an `MSTORE` to
+    /// `0x40`).
+    pub fn overwrites_fmp(&self, j: usize) -> bool {
+        matches!(self.bytecodes[j],Bytecode::Unit(MSTORE)) && self.constant_operand(j,0) == Some(0x40)
+    }
     /// Minimise block information to contain only that which is
     /// deemed "necessary".
     pub fn minimise(&mut self) {
@@ -80,6 +352,13 @@ impl Block {
             s.minimise()
         }
     }
+    /// Minimise memory information only, leaving stack information
+    /// untouched.
+    pub fn minimise_memory(&mut self) {
+        for s in &mut self.states {
+            s.minimise_memory()
+        }
+    }
 }
 
 /// Represents a sequence of basic blocks which are ordered in some
@@ -91,9 +370,25 @@ pub struct BlockSequence {
 
 impl BlockSequence {
     /// Construct a block sequence from a given instruction sequence.
-    pub fn from_insns(n: usize, insns: &[Instruction], precheck: PreconditionFn, limit: usize) -> Self {
-        let mut blocks = insns_to_blocks(n, insns, precheck, limit);
+    /// When `merge` is set, blocks split only because of the block
+    /// size are merged back together (i.e. the block size is treated
+    /// as a soft limit).  When `inline` is set, blocks which simply
+    /// fall through into a `JUMPDEST` include a copy of the block
+    /// which follows, rather than calling it.
+    pub fn from_insns(n: usize, insns: &[Instruction], prechecks: &[PreconditionFn], jumps: &JumpTable, limit: usize, merge: bool, inline: bool) -> Self {
+        // A block size of zero means blocks are never split on size
+        let n = if n == 0 { usize::MAX } else { n };
+        let mut blocks = insns_to_blocks(n, insns, prechecks, jumps, limit);
+        if merge { blocks = merge_straightline(blocks); }
+        if inline { blocks = inline_fallthroughs(blocks,n); }
         determine_necessary_stateinfo(&mut blocks);
+        refine_branches(&mut blocks);
+        determine_unreachable_reasons(&mut blocks);
+        determine_call_free(&mut blocks);
+        determine_jumpdest_targets(&mut blocks);
+        determine_may_write(&mut blocks);
+        determine_stack_values(&mut blocks);
+        determine_stack_underflows(&mut blocks);
         Self{blocks}
     }
     
@@ -108,6 +403,19 @@ impl BlockSequence {
     pub fn to_vec(self) -> Vec<Block> {
         self.blocks
     }
+
+    /// Replace identical blocks by a single shared block, except for
+    /// those starting at one of the given offsets (e.g. roots), which
+    /// must remain addressable.
+    pub fn dedup(&mut self, keep: &[usize]) {
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks = dedup_blocks(blocks,keep);
+    }
+
+    /// Retain only those blocks satisfying a given predicate.
+    pub fn retain<F:FnMut(&Block) -> bool>(&mut self, f: F) {
+        self.blocks.retain(f);
+    }
     
     pub fn minimise(&mut self) {
         // Do it.
@@ -134,16 +442,21 @@ impl BlockState {
         &self.states
     }
     
-    pub fn stack_bounds(&self) -> (usize,usize) {
+    /// Determine the minimum and maximum stack heights across all
+    /// states, or `None` if there are no states (i.e. this point is
+    /// unreachable).
+    pub fn stack_bounds(&self) -> Option<(usize,usize)> {
         let mut min = usize::MAX;
         let mut max = 0;
+        //
+        if self.states.len() == 0 { return None; }
         // 
         for s in &self.states {    
             min = min.min(s.stack().len());
             max = max.max(s.stack().len());        
         }
         //
-        (min,max)        
+        Some((min,max))
     }
 
     pub fn stack_heights(&self) -> Vec<usize> {
@@ -156,9 +469,14 @@ impl BlockState {
         hs
     }
 
+    /// Determine the minimum and maximum free memory pointer values
+    /// across all states, or `None` if there are no states or the
+    /// pointer is unknown in some state.
     pub fn freemem_ptr_bounds(&self) -> Option<(usize,usize)> {
         let mut min = usize::MAX;
         let mut max = 0;
+        //
+        if self.states.len() == 0 { return None; }
         // 
         for s in &self.states {
             match s.freemem_ptr() {
@@ -173,9 +491,34 @@ impl BlockState {
         Some((min,max))
     }
 
+    /// Determine the memory words which are known and the same across
+    /// all states, given as (offset,value) pairs.
+    pub fn memory_words(&self) -> Vec<(usize,w256)> {
+        if self.states.len() == 0 { return Vec::new(); }
+        self.join_states().memory().to_vec()
+    }
+
+    /// Determine the storage slots which are known and the same across
+    /// all states, given as (key,value) pairs.
+    pub fn storage_slots(&self) -> Vec<(w256,w256)> {
+        if self.states.len() == 0 { return Vec::new(); }
+        self.join_states().storage().to_vec()
+    }
+
+    /// Determine the value of the ith stack item, provided it is known
+    /// and the same in every state.
+    pub fn stack_item(&self, i: usize) -> Option<w256> {
+        if self.states.len() == 0 { return None; }
+        self.join_states().stack().get(i).copied().flatten()
+    }
+
     pub fn necessary_stack_item(&self, item: usize) -> bool {
         self.necessary.get(item)
     }
+
+    pub fn necessary_freemem_ptr(&self) -> bool {
+        self.necessary.fmp
+    }
     
     pub fn join_states(&self) -> AbstractState {
         let mut r = self.states[0].clone();
@@ -187,6 +530,23 @@ impl BlockState {
         r
     }
 
+    /// Determine the value of the ith stack item, provided it is the
+    /// same known constant across all states.
+    pub fn operand(&self, index: usize) -> Option<w256> {
+        if self.states.len() == 0 { return None; }
+        operand(index,&self.states)
+    }
+
+    /// Clear the free memory pointer and known memory words (if not
+    /// necessary).  Observe these differ, since the free memory
+    /// pointer is not necessary once overwritten.
+    pub fn minimise_memory(&mut self) {
+        for s in &mut self.states {
+            if !self.necessary.fmp { s.clear_freemem_ptr(); }
+            if !self.necessary.memory { s.clear_memory(); }
+        }
+    }
+
     pub fn clear_stack_item(&mut self, item: usize) {
         for s in &mut self.states {
             s.clear_stack_item(item);
@@ -194,8 +554,12 @@ impl BlockState {
     }
     
     pub fn minimise(&mut self) {
+        self.minimise_memory();
         // Determine max stack height
-        let (_,height) = self.stack_bounds();
+        let height = match self.stack_bounds() {
+            Some((_,max)) => max,
+            None => { return; }
+        };
         //
         for i in 0..height {
             // Check whether ith stack item is necessary (or not).
@@ -213,11 +577,15 @@ impl BlockState {
 
 pub type PreconditionFn = fn(&Instruction,&mut Vec<Bytecode>);
 
+/// Maps the byte offset of a jump to an explicit set of targets to
+/// assume when they cannot be determined by the analysis.
+pub type JumpTable = HashMap<usize,Vec<usize>>;
+
 /// Decompose a given instruction sequence into a block sequence.
 /// This employs an abstract interpretation to determine various key
 /// pieces of information (e.g. jump targets, stack values, etc) at
 /// each point.
-fn insns_to_blocks(n: usize, insns: &[Instruction], precheck: PreconditionFn, limit: usize) -> Vec<Block> {
+fn insns_to_blocks(n: usize, insns: &[Instruction], prechecks: &[PreconditionFn], jumps: &JumpTable, limit: usize) -> Vec<Block> {
     // Compute suplementary information needed for remainder.
     let analysis = BytecodeAnalysis::from_insns(insns, limit).unwrap();
     // Initially empty set of blocks.
@@ -230,7 +598,7 @@ fn insns_to_blocks(n: usize, insns: &[Instruction], precheck: PreconditionFn, li
     while n > 0 && index < insns.len() {
         let block : Block;
         // Process next block
-        (pc,index,block) = insns_to_block(n,pc,index,insns,&analysis,precheck);
+        (pc,index,block) = insns_to_block(n,pc,index,insns,&analysis,prechecks,jumps);
         // Store processed block
         blocks.push(block);
     }
@@ -238,20 +606,298 @@ fn insns_to_blocks(n: usize, insns: &[Instruction], precheck: PreconditionFn, li
     blocks
 }
 
+/// Merge each block into its unique predecessor, where that
+/// predecessor simply falls through into it.  This happens when a
+/// straight-line sequence is split because of the block size, rather
+/// than because of a control-flow join.
+fn merge_straightline(blocks: Vec<Block>) -> Vec<Block> {
+    // Determine number of incoming edges for each block
+    let mut incoming : HashMap<usize,usize> = HashMap::new();
+    for blk in &blocks {
+        for pc in blk.successors() { *incoming.entry(pc).or_insert(0) += 1; }
+    }
+    let mut merged : Vec<Block> = Vec::new();
+    for blk in blocks {
+        let mergeable = match merged.last() {
+            Some(prev) => {
+                prev.next == Some(blk.pc)
+                    && prev.successors() == vec![blk.pc]
+                    && !prev.bytecodes.iter().any(|bc| matches!(bc,Bytecode::Unit(JUMP|JUMPI)))
+                    && incoming.get(&blk.pc) == Some(&1)
+                    && !matches!(blk.bytecodes.first(),Some(Bytecode::Unit(JUMPDEST)))
+            }
+            None => false
+        };
+        if mergeable {
+            let prev = merged.last_mut().unwrap();
+            prev.bytecodes.extend(blk.bytecodes);
+            prev.states.extend(blk.states);
+            prev.next = blk.next;
+        } else {
+            merged.push(blk);
+        }
+    }
+    merged
+}
+
+/// Inline a copy of each block beginning with a `JUMPDEST` into any
+/// predecessor which simply falls through into it, provided the
+/// result is within the block size.  The original block is retained
+/// only when it has other predecessors, since otherwise it would be
+/// unreachable.  Edges within a loop are not inlined, and only one
+/// level of inlining is performed.
+fn inline_fallthroughs(blocks: Vec<Block>, n: usize) -> Vec<Block> {
+    let offsets : HashMap<usize,usize> = blocks.iter().enumerate().map(|(i,b)| (b.pc,i)).collect();
+    // Determine blocks reached by some jump (i.e. not only by falling
+    // through into them)
+    let jumped : Vec<usize> = blocks.iter().flat_map(|b| b.bytecodes.iter()).flat_map(|bc| match bc {
+        Bytecode::Jump(ts)|Bytecode::JumpI(ts)|Bytecode::RJump(ts)|Bytecode::RJumpI(ts) => ts.clone(),
+        _ => Vec::new()
+    }).collect();
+    let mut edges = Vec::new();
+    let mut component = HashMap::new();
+    for (k,scc) in crate::cfg::sccs(&blocks).into_iter().enumerate() {
+        for pc in scc { component.insert(pc,k); }
+    }
+    let mut inlined = blocks.clone();
+    for (i,blk) in blocks.iter().enumerate() {
+        let j = match blk.next.and_then(|pc| offsets.get(&pc)) {
+            Some(j) => *j,
+            None => { continue; }
+        };
+        let succ = &blocks[j];
+        let inlineable = blk.successors() == vec![succ.pc]
+            && !blk.bytecodes.iter().any(|bc| matches!(bc,Bytecode::Unit(JUMP|JUMPI)))
+            && matches!(succ.bytecodes.first(),Some(Bytecode::Unit(JUMPDEST)))
+            && component.get(&blk.pc) != component.get(&succ.pc)
+            && blk.bytecodes.len() + succ.bytecodes.len() <= n;
+        if inlineable {
+            inlined[i].bytecodes.extend(succ.bytecodes.iter().cloned());
+            inlined[i].states.extend(succ.states.iter().cloned());
+            inlined[i].next = succ.next;
+            edges.push((blk.pc,succ.pc));
+        }
+    }
+    // Drop blocks which are now only reached through their copy,
+    // unless the block copied into was itself dropped (since then
+    // the copy of that block still falls through).
+    let mut dropped = Vec::new();
+    for (from,to) in edges {
+        if !jumped.contains(&to) && !dropped.contains(&from) { dropped.push(to); }
+    }
+    inlined.retain(|b| !dropped.contains(&b.pc));
+    inlined
+}
+
+/// Determine, for each unreachable block, why it is unreachable.  This
+/// distinguishes blocks with no incoming edges at all from those
+/// whose incoming edges are never taken.
+fn determine_unreachable_reasons(blocks: &mut [Block]) {
+    // Map each block to whether any reachable / unreachable block
+    // has an edge to it.
+    let mut incoming : HashMap<usize,(bool,bool)> = HashMap::new();
+    //
+    for blk in blocks.iter() {
+        let live = !blk.is_unreachable();
+        for pc in blk.successors() {
+            let e = incoming.entry(pc).or_insert((false,false));
+            if live { e.0 = true; } else { e.1 = true; }
+        }
+    }
+    //
+    for blk in blocks.iter_mut() {
+        if blk.is_unreachable() {
+            blk.dead = match incoming.get(&blk.pc) {
+                Some((true,_)) => Some(Unreachable::EdgeNeverTaken),
+                Some((false,true)) => Some(Unreachable::DeadPredecessors),
+                _ => Some(Unreachable::NoIncomingEdges)
+            };
+        }
+    }
+}
+
+/// Determine which blocks can only be entered before any call is
+/// made.  That is, blocks which are not reachable from any (live)
+/// block containing a call.
+fn determine_call_free(blocks: &mut [Block]) {
+    let offsets : HashMap<usize,usize> = blocks.iter().enumerate().map(|(i,b)| (b.pc(),i)).collect();
+    let mut tainted = vec![false; blocks.len()];
+    let mut worklist = Vec::new();
+    // Seed with successors of any block which makes a call
+    for blk in blocks.iter() {
+        let calls = blk.iter().any(|bc| {
+            matches!(bc,Bytecode::Unit(CALL|CALLCODE|DELEGATECALL|STATICCALL|CREATE|CREATE2))
+        });
+        if calls && !blk.is_unreachable() { worklist.extend(blk.successors()); }
+    }
+    // Propagate
+    while let Some(pc) = worklist.pop() {
+        if let Some(i) = offsets.get(&pc) {
+            if !tainted[*i] {
+                tainted[*i] = true;
+                worklist.extend(blocks[*i].successors());
+            }
+        }
+    }
+    //
+    for (blk,t) in blocks.iter_mut().zip(tainted) {
+        blk.call_free = !t && !blk.is_unreachable();
+    }
+}
+
+/// Replace blocks which are identical (i.e. same bytecodes and same
+/// information on entry) by a single block, whose method is then
+/// shared.  Only blocks whose behaviour does not depend on their
+/// offset are considered (e.g. those which don't fall through, use
+/// `PC` or branch conditionally).  Blocks starting at an offset in
+/// `keep` are never replaced.
+fn dedup_blocks(blocks: Vec<Block>, keep: &[usize]) -> Vec<Block> {
+    let mut canonical : HashMap<String,usize> = HashMap::new();
+    let mut redirects : HashMap<usize,usize> = HashMap::new();
+    let mut deduped : Vec<Block> = Vec::new();
+    for blk in blocks {
+        let independent = blk.next.is_none() && !blk.bytecodes.iter().any(|bc| {
+            matches!(bc,Bytecode::JumpI(_)|Bytecode::RJump(_)|Bytecode::RJumpI(_)|Bytecode::Unit(PC|JUMP|JUMPI))
+        });
+        if blk.pc != 0 && !keep.contains(&blk.pc) && independent {
+            let key = dedup_key(&blk);
+            match canonical.get(&key) {
+                Some(i) => {
+                    redirects.insert(blk.pc,deduped[*i].pc);
+                    deduped[*i].aliases.push(blk.pc);
+                    continue;
+                }
+                None => { canonical.insert(key,deduped.len()); }
+            }
+        }
+        deduped.push(blk);
+    }
+    // Redirect any successors which were removed
+    for blk in &mut deduped {
+        for pc in blk.successors() {
+            if let Some(t) = redirects.get(&pc) { blk.redirects.push((pc,*t)); }
+        }
+    }
+    deduped
+}
+
+/// Determine the key under which blocks are compared for
+/// deduplication.  This consists of the bytecodes along with
+/// everything known on entry which affects the generated method, with
+/// offsets taken relative to the block.  Ownership is not included,
+/// since it is determined afterwards.
+fn dedup_key(blk: &Block) -> String {
+    let underflow = blk.underflow.map(|pc| pc - blk.pc);
+    format!("{:?}",(&blk.bytecodes,&blk.states,&blk.facts,&blk.values,blk.dead,blk.call_free,blk.loop_header,blk.in_loop,&blk.jumpdests,blk.may_write,underflow))
+}
+
+/// Determine, for each block, whether it may modify state.  This holds
+/// if the block itself contains a state-modifying instruction, or it
+/// can reach such a block.  Blocks containing unsupported instructions
+/// or unresolved jumps are conservatively assumed to modify state.
+fn determine_may_write(blocks: &mut [Block]) {
+    let offsets : HashMap<usize,usize> = blocks.iter().enumerate().map(|(i,b)| (b.pc(),i)).collect();
+    let mut preds = vec![Vec::new(); blocks.len()];
+    let mut worklist = Vec::new();
+    for (i,blk) in blocks.iter().enumerate() {
+        for pc in blk.successors() {
+            match offsets.get(&pc) {
+                Some(j) => { preds[*j].push(i); }
+                None => { worklist.push(i); }
+            }
+        }
+        // Functions in other code sections are assumed to write
+        let writes = blk.iter().any(|bc| {
+            matches!(bc,Bytecode::Unit(SSTORE|TSTORE|LOG(_)|CALL|CALLCODE|DELEGATECALL|CREATE|CREATE2|SELFDESTRUCT|JUMP|JUMPI|CALLF(_)))
+        });
+        if writes || blk.may_write { worklist.push(i); }
+    }
+    // Propagate backwards to predecessors
+    let mut visited = vec![false; blocks.len()];
+    while let Some(i) = worklist.pop() {
+        if !visited[i] {
+            visited[i] = true;
+            blocks[i].may_write = true;
+            worklist.extend(preds[i].iter().copied());
+        }
+    }
+}
+
+/// Determine, for each (reachable) block, whether some bytecode within
+/// it pops more items than are available.  This simulates the stack
+/// height through the block, starting from the minimum height on
+/// entry.  Any underflow is reported, since the generated proof for
+/// that block cannot verify.
+fn determine_stack_underflows(blocks: &mut [Block]) {
+    for blk in blocks.iter_mut() {
+        let mut height = match blk.stack_bounds() {
+            Some((min,_)) => min as isize,
+            None => { continue; }
+        };
+        let mut pc = blk.pc;
+        for bc in &blk.bytecodes {
+            let (n,m) = stack_effect(bc);
+            if height < n as isize {
+                eprintln!("WARNING: stack underflow at {pc:#x}");
+                blk.underflow = Some(pc);
+                break;
+            }
+            height += (m as isize) - (n as isize);
+            pc += bc.length();
+        }
+    }
+}
+
+/// Determine the number of operands consumed, and results produced,
+/// by a given bytecode.
+fn stack_effect(bc: &Bytecode) -> (usize,usize) {
+    match bc {
+        Bytecode::Comment(_)|Bytecode::Havoc(_)|Bytecode::Assert(_,_)|Bytecode::RJump(_) => (0,0),
+        Bytecode::Jump(_)|Bytecode::RJumpI(_) => (1,0),
+        Bytecode::Mask(_) => (2,1),
+        Bytecode::JumpI(_) => (2,0),
+        // Duplicates and swaps reach into, but leave intact, the items
+        // beneath the top of the stack.
+        Bytecode::Unit(DUP(n)) => (*n as usize,(*n as usize) + 1),
+        Bytecode::Unit(SWAP(n)) => ((*n as usize) + 1,(*n as usize) + 1),
+        Bytecode::Unit(insn) => (insn.operands(),insn_produces(insn))
+    }
+}
+
+/// Determine, for each block, which of its jump targets are known to
+/// begin with a `JUMPDEST` instruction (i.e. are valid destinations).
+fn determine_jumpdest_targets(blocks: &mut [Block]) {
+    let jumpdests : Vec<usize> = blocks.iter().filter(|b| b.is_jumpdest()).map(|b| b.pc).collect();
+    //
+    for blk in blocks.iter_mut() {
+        let mut targets = Vec::new();
+        for bc in &blk.bytecodes {
+            if let Bytecode::Jump(ts)|Bytecode::JumpI(ts) = bc {
+                targets.extend(ts.iter().filter(|t| jumpdests.contains(t)));
+            }
+        }
+        targets.sort_unstable();
+        targets.dedup();
+        blk.jumpdests = targets;
+    }
+}
+
 /// Extract the next block starting at a given byte offset (and
 /// instruction offset) within the original sequence.
-fn insns_to_block(mut n: usize, mut pc: usize, index: usize, insns: &[Instruction], analysis: &BytecodeAnalysis, precheck: PreconditionFn) -> (usize,usize,Block) {
+fn insns_to_block(mut n: usize, mut pc: usize, index: usize, insns: &[Instruction], analysis: &BytecodeAnalysis, prechecks: &[PreconditionFn], jumps: &JumpTable) -> (usize,usize,Block) {
     let mut i = index;    
     // Construct (initially) empty block
-    let mut block = Block{pc,states: Vec::new(), bytecodes: Vec::new(),next: None};
+    let mut block = Block{pc,states: Vec::new(), bytecodes: Vec::new(),next: None, facts: Vec::new(), dead: None, values: Vec::new(), owners: Vec::new(), shared: false, call_free: false, loop_header: false, in_loop: false, jumpdests: Vec::new(), may_write: false, aliases: Vec::new(), redirects: Vec::new(), underflow: None};
     // Flag to signal early exit
     let mut done = false;
     // Travese block to its end
     while !done && i < insns.len() && n > 0 {
         let insn = &insns[i];
         let mut bc : Bytecode;
-        // Insert any precondition checks
-        precheck(insn, &mut block.bytecodes);
+        // Insert any precondition checks (in order)
+        for precheck in prechecks {
+            precheck(insn, &mut block.bytecodes);
+        }
         // Convert bytecode                
         match insn {
             JUMPDEST => {
@@ -268,9 +914,39 @@ fn insns_to_block(mut n: usize, mut pc: usize, index: usize, insns: &[Instructio
                     break;
                 }
             }            
+            RJUMP(_)|RJUMPI(_) => {
+                // Relative jumps have static targets
+                let targets = analysis.get_targets(i).to_vec();
+                if matches!(insn,RJUMP(_)) {
+                    // Terminating instruction
+                    done = true;
+                    bc = Bytecode::RJump(targets);
+                } else {
+                    bc = Bytecode::RJumpI(targets);
+                }
+            }
+            _ if !is_supported(insn) => {
+                // Unsupported instructions cannot be modelled, hence
+                // any verification of this block must fail.
+                println!("WARNING: unsupported instruction {insn} at {pc:#x}");
+                block.bytecodes.push(Bytecode::Comment(format!("UNSUPPORTED {insn}")));
+                bc = Bytecode::Assert(Vec::new(),"false".to_string());
+                block.may_write = true;
+                done = true;
+            }
             _ => {
+                let assumed = jumps.get(&pc);
+                // Make any assumed jump targets explicit (where used)
+                let unknown = analysis.get_states(i).iter().any(|s| s.stack()[0].is_none());
+                if assumed.is_some() && unknown && matches!(insn,JUMP|JUMPI) {
+                    block.bytecodes.push(Bytecode::Comment("Jump targets assumed (--jump-targets)".to_string()));
+                }
                 // Translate any other kind of instruction
-                (bc,done) = translate_insn(insn,done,analysis.get_states(i));
+                (bc,done) = translate_insn(insn,done,analysis.get_states(i),assumed);
+                // Jumps remain untranslated when their targets are unknown
+                if matches!(bc,Bytecode::Unit(JUMP|JUMPI)) {
+                    println!("WARNING: unresolved jump at {pc:#x} (see --jump-targets)");
+                }
             }
         };
         block.bytecodes.push(bc);
@@ -290,7 +966,7 @@ fn insns_to_block(mut n: usize, mut pc: usize, index: usize, insns: &[Instructio
     (pc,i,block)
 }
 
-fn translate_insn(insn: &Instruction, mut done: bool, states: &[AbstractState]) -> (Bytecode,bool) {
+fn translate_insn(insn: &Instruction, mut done: bool, states: &[AbstractState], assumed: Option<&Vec<usize>>) -> (Bytecode,bool) {
     let bc = match insn {
         AND => {
             match &operand(0,states) {
@@ -302,23 +978,28 @@ fn translate_insn(insn: &Instruction, mut done: bool, states: &[AbstractState])
         }
         HAVOC(n) => {
             // Virtual instructions
-            Bytecode::Comment(format!("Havoc {n}"))
+            Bytecode::Havoc(*n as usize)
         }
         JUMPI => {
             // Extract branch targets
-            let targets = jump_targets(states);
-            // 
-            Bytecode::JumpI(targets)
+            match jump_targets(states,assumed) {
+                Some(targets) => Bytecode::JumpI(targets),
+                None => Bytecode::Unit(insn.clone())
+            }
         }
         JUMP => {
-            // Extract branch targets
-            let targets = jump_targets(states);
             // Terminating instruction
             done = true;
-            // 
-            Bytecode::Jump(targets)                    
+            // Extract branch targets
+            match jump_targets(states,assumed) {
+                Some(targets) => Bytecode::Jump(targets),
+                None => Bytecode::Unit(insn.clone())
+            }
+        }
+        RJUMPI(_)|RJUMP(_) => {
+            // Handled separately, since relative targets depend on pc
+            unreachable!()
         }
-        RJUMPI(_)|RJUMP(_) => { todo!() }
         DATA(bytes) => {
             done = true;
             Bytecode::Unit(insn.clone())            
@@ -331,21 +1012,25 @@ fn translate_insn(insn: &Instruction, mut done: bool, states: &[AbstractState])
     (bc,done)
 }
 
-/// Extract the set of possible jump targets from a given abstract
-/// state.  That is, the set of possible values on top of the stack in
-/// the given state.
-fn jump_targets(states: &[AbstractState]) -> Vec<usize> {
+/// Extract the set of possible jump targets across all states.  That
+/// is, the set of possible values on top of the stack.  Where the
+/// target is unknown in some state, the given assumed targets (if any)
+/// are used instead.  Otherwise, this returns `None`.  In practice,
+/// this can happen in some unusual cases (e.g. the jump target is
+/// loaded out of memory or storage) and should be resolved with
+/// `--jump-targets`.
+fn jump_targets(states: &[AbstractState], assumed: Option<&Vec<usize>>) -> Option<Vec<usize>> {
     let mut targets :Vec<usize> = Vec::new();
     for s in states {
-        // NOTE: this will fail if the branch target is unknown.  For
-        // now, I just assume this can never happen.  However, in
-        // practice, it could happen in some unusual cases (e.g. the
-        // jump target is loaded out of memory or storage).
-        targets.push(s.stack()[0].unwrap().to());
+        match (s.stack()[0],assumed) {
+            (Some(t),_) => targets.push(t.to()),
+            (None,Some(ts)) => targets.extend(ts),
+            (None,None) => { return None; }
+        }
     }
     targets.sort_unstable();
     targets.dedup();
-    targets
+    Some(targets)
 }
 
 /// Extract a single value (where applicable) for a given item on the
@@ -394,11 +1079,17 @@ fn as_bit_mask(w: &w256) -> usize {
 /// Contains information relevant to a given block during the
 /// minimisation procedure.
 #[derive(Clone,Debug)]
-struct NecessaryState { stack: Vec<bool> }
+struct NecessaryState { stack: Vec<bool>, fmp: bool, memory: bool }
 
 impl NecessaryState {
     pub fn new() -> Self {
-        Self{stack: Vec::new()}
+        Self{stack: Vec::new(), fmp: false, memory: false}
+    }
+
+    /// Construct a state where everything is necessary (i.e. up to
+    /// the maximum EVM stack height).
+    pub fn all() -> Self {
+        Self{stack: vec![true; 1024], fmp: true, memory: true}
     }
     
     // Check whether the given stack item was used or not.
@@ -439,7 +1130,9 @@ impl NecessaryState {
 	while self.stack.len() < n { self.stack.insert(0,false); }
 	// Now perform the merge
 	let m = self.stack.len() - other.stack.len();
-	let mut changed = false;
+	let mut changed = (!self.fmp && other.fmp) || (!self.memory && other.memory);
+	self.fmp |= other.fmp;
+	self.memory |= other.memory;
 	for i in 0 .. other.stack.len() {
 	    let old = self.stack[i+m];
 	    self.stack[i+m] |= other.stack[i];
@@ -449,7 +1142,20 @@ impl NecessaryState {
     }    
 }
 
+/// Recompute the necessary information to perform state minimisation
+/// over a given subset of blocks (e.g. a single group), rather than
+/// over the whole code section.  Successors outside the subset are
+/// treated as needing everything.
+pub fn scope_necessary_stateinfo(blocks: &mut [Block]) {
+    for blk in blocks.iter_mut() {
+        for s in &mut blk.states { s.necessary = NecessaryState::new(); }
+    }
+    determine_necessary_stateinfo(blocks);
+}
+
 /// Construct the necessary information to perform state minimisation.
+/// Successors outside the given blocks are treated as needing
+/// everything.
 fn determine_necessary_stateinfo(blocks: &mut [Block]) {
     let n = blocks.len();
     let mut offsets = HashMap::new();
@@ -459,37 +1165,69 @@ fn determine_necessary_stateinfo(blocks: &mut [Block]) {
         // Map block address to block index.
         offsets.insert(blk.pc(),i);
     }
-    // Iterative dataflow analysis algorithm :)
-    let mut changed = true;
-    let mut counter = 100;
-    while changed && counter > 0 {
-        changed = false;        
-        // Iterate backwards
-        for i in (0..n).into_iter().rev() {
-            // Determine incoming state
-            let mut state = match blocks[i].next() {
-                None => NecessaryState::new(),
-                Some(pc) => {
-                    blocks[*offsets.get(&pc).unwrap()].states[0].necessary.clone()
-                }
-            };
-            // Iterate bytecodes in reverse
-            let m = blocks[i].bytecodes().len();
-            for j in (0..m).into_iter().rev() {
-                let b = &blocks[i].bytecodes[j];
-                // Apply effect of bytecode (in reverse)
-                state = transfer_bytecode(b,state,&blocks,&offsets);
-                // Now merge it in
-                changed |= blocks[i].states[j].necessary.join(&state);                
+    // Determine predecessors of every block
+    let mut preds = vec![Vec::new(); n];
+    for i in 0..n {
+        for pc in blocks[i].successors() {
+            if let Some(j) = offsets.get(&pc) { preds[*j].push(i); }
+        }
+    }
+    // Worklist dataflow analysis algorithm :)  Blocks are initially
+    // processed backwards, and thereafter only when the entry state of
+    // some successor has changed.
+    let mut worklist : Vec<usize> = (0..n).collect();
+    let mut queued = vec![true; n];
+    // Guard against non-termination
+    let mut budget = 1000 * n;
+    while let Some(i) = worklist.pop() {
+        queued[i] = false;
+        if budget == 0 {
+            println!("WARNING: necessary state analysis did not converge (block {:#06x})",blocks[i].pc());
+            // Minimisation would be unsound, hence retain everything
+            for blk in blocks.iter_mut() {
+                for s in &mut blk.states { s.necessary = NecessaryState::all(); }
+            }
+            return;
+        }
+        budget -= 1;
+        // Determine incoming state
+        let mut state = match blocks[i].next().map(|pc| offsets.get(&pc)) {
+            None => NecessaryState::new(),
+            Some(Some(j)) => blocks[*j].states[0].necessary.clone(),
+            // Blocks outside the sequence may need everything
+            Some(None) => NecessaryState::all()
+        };
+        // Iterate bytecodes in reverse
+        let mut changed = false;
+        let m = blocks[i].bytecodes().len();
+        for j in (0..m).into_iter().rev() {
+            let b = &blocks[i].bytecodes[j];
+            // Free memory pointer not needed before being overwritten
+            if blocks[i].overwrites_fmp(j) { state.fmp = false; }
+            // Apply effect of bytecode (in reverse)
+            state = transfer_bytecode(b,state,&blocks,&offsets);
+            // Now merge it in
+            changed = blocks[i].states[j].necessary.join(&state);                
+        }
+        // Entry state changed, so reprocess predecessors
+        if changed {
+            for p in &preds[i] {
+                if !queued[*p] { queued[*p] = true; worklist.push(*p); }
             }
         }
-	counter -= 1;
     }
 }
 
 fn transfer_bytecode(bytecode: &Bytecode, mut state: NecessaryState, blocks: &[Block], offsets: &HashMap<usize,usize>) -> NecessaryState {
+    // Anything which reads memory may depend on the free memory
+    // pointer, or on known memory words.
+    if reads_memory(bytecode) {
+        state.fmp = true;
+        state.memory = true;
+    }
+    //
     match bytecode {
-	Bytecode::Comment(_) => { state }
+	Bytecode::Comment(_)|Bytecode::Havoc(_) => { state }
 	Bytecode::Assert(deps,_) => {
 	    for dep in deps {
 		state.set(*dep,true);
@@ -511,6 +1249,13 @@ fn transfer_bytecode(bytecode: &Bytecode, mut state: NecessaryState, blocks: &[B
 	    state.set(n,tmp);
 	    state
 	}
+	Bytecode::Unit(MCOPY) => {
+	    // Destination, source and length all affect memory
+	    state.push(true);
+	    state.push(true);
+	    state.push(true);
+	    state
+	}
 	Bytecode::Unit(MSTORE|MSTORE8) => {
 	    // Put things on the stack
 	    state.push(false); // value lost
@@ -554,6 +1299,26 @@ fn transfer_bytecode(bytecode: &Bytecode, mut state: NecessaryState, blocks: &[B
 	    state.push(true); // target pc
 	    state
 	}
+	Bytecode::RJumpI(targets) => {
+	    let targets = merge_target_states(targets,blocks,offsets);
+	    state.join(&targets);
+	    state.push(false); // condition
+	    state
+	}
+	Bytecode::RJump(targets) => {
+	    let targets = merge_target_states(targets,blocks,offsets);
+	    state.join(&targets);
+	    state
+	}
+    }
+}
+
+/// Check whether a given bytecode (potentially) reads memory.
+fn reads_memory(bytecode: &Bytecode) -> bool {
+    match bytecode {
+        Bytecode::Unit(MLOAD|MCOPY|KECCAK256|RETURN|REVERT|LOG(_)) => true,
+        Bytecode::Unit(CALL|CALLCODE|DELEGATECALL|STATICCALL|CREATE|CREATE2) => true,
+        _ => false
     }
 }
 
@@ -561,8 +1326,11 @@ fn merge_target_states(targets: &[usize], blocks: &[Block], offsets: &HashMap<us
     let mut state = NecessaryState::new();
     
     for pc in targets {
-	let bid = offsets.get(pc).unwrap();
-	state.join(&blocks[*bid].states[0].necessary);
+	match offsets.get(pc) {
+	    Some(bid) => { state.join(&blocks[*bid].states[0].necessary); }
+	    // Blocks outside the sequence may need everything
+	    None => { state.join(&NecessaryState::all()); }
+	}
     }
     // done
     state
@@ -570,8 +1338,23 @@ fn merge_target_states(targets: &[usize], blocks: &[Block], offsets: &HashMap<us
 
 // Determines how many stack items are produced by the given
 // instruction.
-fn insn_produces(insn: &Instruction) -> usize {
-    match insn {
+pub fn insn_produces(insn: &Instruction) -> usize {
+    match try_insn_produces(insn) {
+        Some(n) => n,
+        None => { unreachable!("{:?}",insn); }
+    }
+}
+
+/// Check whether a given instruction is supported (i.e. its effect on
+/// the stack is known).
+pub fn is_supported(insn: &Instruction) -> bool {
+    try_insn_produces(insn).is_some()
+}
+
+// Determines how many stack items are produced by the given
+// instruction, or `None` if the instruction is not supported.
+fn try_insn_produces(insn: &Instruction) -> Option<usize> {
+    let n = match insn {
         STOP => 0,
         ADD|MUL|SUB|DIV|SDIV|MOD|SMOD|EXP|SIGNEXTEND => 1,
         ADDMOD|MULMOD => 1,
@@ -587,7 +1370,7 @@ fn insn_produces(insn: &Instruction) -> usize {
         COINBASE|TIMESTAMP|NUMBER|DIFFICULTY|GASLIMIT|CHAINID|SELFBALANCE => 1,
         // 50s: Stack, Memory, Storage and Flow Operations
         MSIZE|PC|GAS|MLOAD|SLOAD|TLOAD => 1,
-	JUMPDEST|POP|JUMP|JUMPI|SSTORE|MSTORE|MSTORE8|TSTORE => 0,     
+	JUMPDEST|POP|JUMP|JUMPI|SSTORE|MSTORE|MSTORE8|TSTORE|MCOPY => 0,     
         // 60s & 70s: Push Operations            
         PUSH0|PUSH(_) => 1,
         // 80s: Duplication Operations
@@ -604,9 +1387,229 @@ fn insn_produces(insn: &Instruction) -> usize {
         CREATE2 => 1,            
         DELEGATECALL|STATICCALL => 1,            
         CALL|CALLCODE => 1,
+        // EOF functions (where the items passed to and returned from
+        // a function are given by its type, which is not modelled)
+        CALLF(_)|RETF => 0,
         // Virtual instructions
         HAVOC(_) => 0,
         DATA(_) => 0,
-        _ => { unreachable!("{:?}",insn); }
+        _ => { return None; }
+    };
+    Some(n)
+}
+
+// ===================================================================
+// Tests
+// ===================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Construct the blocks for a given instruction sequence, using a
+    /// given table of assumed jump targets.
+    fn blocks(insns: &[Instruction], jumps: &JumpTable) -> Vec<Block> {
+        BlockSequence::from_insns(0,insns,&[],jumps,usize::MAX,false,false).to_vec()
+    }
+
+    /// Check whether a given block contains a given comment.
+    fn has_comment(block: &Block, comment: &str) -> bool {
+        block.iter().any(|bc| matches!(bc,Bytecode::Comment(s) if s == comment))
+    }
+
+    #[test]
+    fn test_jump_targets_01() {
+        // Assumed targets unused when target is known
+        let insns = vec![PUSH(vec![0x4]),JUMP,STOP,JUMPDEST,STOP];
+        let jumps = JumpTable::from([(2,vec![4])]);
+        let bs = blocks(&insns,&jumps);
+        assert!(!has_comment(&bs[0],"Jump targets assumed (--jump-targets)"));
+        assert!(matches!(bs[0].bytecodes().last(),Some(Bytecode::Jump(ts)) if ts == &vec![4]));
+    }
+
+    #[test]
+    fn test_jump_targets_02() {
+        // Assumed targets used when target is unknown
+        let insns = vec![PUSH(vec![0x0]),CALLDATALOAD,JUMP,JUMPDEST,STOP];
+        let jumps = JumpTable::from([(3,vec![4])]);
+        let bs = blocks(&insns,&jumps);
+        assert!(has_comment(&bs[0],"Jump targets assumed (--jump-targets)"));
+        assert!(matches!(bs[0].bytecodes().last(),Some(Bytecode::Jump(ts)) if ts == &vec![4]));
+    }
+
+    #[test]
+    fn test_necessary_stateinfo_01() {
+        // A loop through a chain of n blocks, each jumping to the
+        // block before it, where only the first block uses the item
+        // on the stack.  Necessity therefore flows against the order
+        // in which blocks are processed, one block at a time.
+        let n = 120;
+        let chain = |k: usize| (15 + 5*k) as u16;
+        let mut insns = vec![PUSH(vec![0x2a]),PUSH(chain(n-1).to_be_bytes().to_vec()),JUMP];
+        // Use the item at 0x06
+        insns.extend([JUMPDEST,DUP1,PUSH(vec![0x0]),MSTORE,PUSH(chain(n-1).to_be_bytes().to_vec()),JUMP]);
+        // Chain of blocks from 0x0f
+        for k in 0..n {
+            let target = if k == 0 { 0x06 } else { chain(k-1) };
+            insns.extend([JUMPDEST,PUSH(target.to_be_bytes().to_vec()),JUMP]);
+        }
+        let bs = blocks(&insns,&JumpTable::new());
+        assert_eq!(bs.len(),n+2);
+        for blk in &bs[1..] {
+            assert!(blk.state(0).necessary_stack_item(0),"item not needed at {:#06x}",blk.pc());
+        }
+    }
+
+    #[test]
+    fn test_mcopy_01() {
+        // All operands of MCOPY are needed
+        let insns = vec![PUSH(vec![0x20]),PUSH(vec![0x0]),PUSH(vec![0x40]),MCOPY,STOP];
+        let bs = blocks(&insns,&JumpTable::new());
+        let i = bs[0].iter().position(|bc| matches!(bc,Bytecode::Unit(MCOPY))).unwrap();
+        for k in 0..3 {
+            assert!(bs[0].state(i).necessary_stack_item(k));
+        }
+    }
+
+    #[test]
+    fn test_merge_straightline_01() {
+        // Straight-line code split by a block size of two
+        let insns = vec![PUSH(vec![0x1]),PUSH(vec![0x2]),ADD,STOP];
+        let split = BlockSequence::from_insns(2,&insns,&[],&JumpTable::new(),usize::MAX,false,false).to_vec();
+        assert_eq!(split.len(),2);
+        assert_eq!(split[0].next,Some(4));
+        // Merged back into one block
+        let merged = BlockSequence::from_insns(2,&insns,&[],&JumpTable::new(),usize::MAX,true,false).to_vec();
+        assert_eq!(merged.len(),1);
+        assert_eq!(merged[0].pc(),0);
+        assert_eq!(merged[0].next,None);
+        assert_eq!(merged[0].bytecodes().len(),split[0].bytecodes().len() + split[1].bytecodes().len());
+    }
+
+    #[test]
+    fn test_merge_straightline_02() {
+        // Blocks joined at a JUMPDEST are not merged
+        let insns = vec![PUSH(vec![0x1]),PUSH(vec![0x2]),JUMPDEST,STOP];
+        let merged = BlockSequence::from_insns(2,&insns,&[],&JumpTable::new(),usize::MAX,true,false).to_vec();
+        assert_eq!(merged.len(),2);
+    }
+
+    #[test]
+    fn test_scope_necessary_stateinfo_01() {
+        // An isolated function (0x00 and 0x07) alongside an unrelated
+        // block (0x0a).
+        let insns = vec![PUSH(vec![0x1]),PUSH(vec![0x2]),PUSH(vec![0x7]),JUMP,
+                         JUMPDEST,POP,STOP,
+                         JUMPDEST,PUSH(vec![0x3]),POP,STOP];
+        let bs = blocks(&insns,&JumpTable::new());
+        assert_eq!(bs.iter().map(|b| b.pc()).collect::<Vec<_>>(),vec![0x0,0x7,0xa]);
+        // Scoping to the function clears the same items
+        let mut group = bs[0..2].to_vec();
+        scope_necessary_stateinfo(&mut group);
+        for (b,g) in bs.iter().zip(&group) {
+            for (s,t) in b.states.iter().zip(&g.states) {
+                assert_eq!(format!("{:?}",s.necessary),format!("{:?}",t.necessary));
+            }
+        }
+        assert!(!group[1].state(0).necessary_stack_item(1));
+    }
+
+    #[test]
+    fn test_inline_fallthroughs_01() {
+        // Block only reached by falling through is dropped once inlined
+        let insns = vec![PUSH(vec![0x1]),POP,JUMPDEST,STOP];
+        let bs = BlockSequence::from_insns(0,&insns,&[],&JumpTable::new(),usize::MAX,false,true).to_vec();
+        assert_eq!(bs.len(),1);
+        assert!(!bs[0].is_unreachable());
+        assert!(matches!(bs[0].bytecodes().last(),Some(Bytecode::Unit(STOP))));
+    }
+
+    #[test]
+    fn test_inline_fallthroughs_02() {
+        // Block also reached by a jump is retained, and remains
+        // reachable.
+        let insns = vec![PUSH(vec![0x0]),CALLDATALOAD,PUSH(vec![0x9]),JUMPI,PUSH(vec![0x1]),POP,JUMPDEST,STOP];
+        let bs = BlockSequence::from_insns(0,&insns,&[],&JumpTable::new(),usize::MAX,false,true).to_vec();
+        assert_eq!(bs.iter().map(|b| b.pc()).collect::<Vec<_>>(),vec![0x0,0x9]);
+        assert!(matches!(bs[0].bytecodes().last(),Some(Bytecode::Unit(STOP))));
+        assert!(bs.iter().all(|b| !b.is_unreachable() && b.unreachable_reason().is_none()));
+    }
+
+    #[test]
+    fn test_blocksize_01() {
+        // Straight-line sequence longer than the default block size
+        let mut insns = Vec::new();
+        for _ in 0..40000 { insns.extend([PUSH(vec![0x1]),POP]); }
+        insns.push(STOP);
+        let bs = BlockSequence::from_insns(0,&insns,&[],&JumpTable::new(),usize::MAX,false,false).to_vec();
+        assert_eq!(bs.len(),1);
+        assert_eq!(bs[0].next(),None);
+        // Split when bounded
+        let bs = BlockSequence::from_insns(65535,&insns,&[],&JumpTable::new(),usize::MAX,false,false).to_vec();
+        assert_eq!(bs.len(),2);
+        assert_eq!(bs[0].next(),Some(bs[1].pc()));
+    }
+
+    #[test]
+    fn test_dedup_01() {
+        // Two identical blocks at 0x9 and 0xb share a single method
+        let insns = vec![PUSH(vec![0x0]),CALLDATALOAD,PUSH(vec![0x9]),JUMPI,PUSH(vec![0xb]),JUMP,JUMPDEST,STOP,JUMPDEST,STOP];
+        let mut bs = BlockSequence::from_insns(0,&insns,&[],&JumpTable::new(),usize::MAX,false,false);
+        bs.dedup(&[]);
+        let bs = bs.to_vec();
+        let pcs : Vec<usize> = bs.iter().map(|b| b.pc()).collect();
+        assert_eq!(pcs,vec![0x0,0x6,0x9]);
+        assert_eq!(bs[2].aliases(),&[0xb]);
+        assert_eq!(bs[1].callee(0xb),0x9);
+    }
+
+    #[test]
+    fn test_dedup_02() {
+        // Blocks which must remain addressable are never replaced
+        let insns = vec![PUSH(vec![0x0]),CALLDATALOAD,PUSH(vec![0x9]),JUMPI,PUSH(vec![0xb]),JUMP,JUMPDEST,STOP,JUMPDEST,STOP];
+        let mut bs = BlockSequence::from_insns(0,&insns,&[],&JumpTable::new(),usize::MAX,false,false);
+        bs.dedup(&[0xb]);
+        let bs = bs.to_vec();
+        assert_eq!(bs.len(),4);
+        assert!(bs.iter().all(|b| b.aliases().is_empty()));
+        assert_eq!(bs[1].callee(0xb),0xb);
+    }
+
+    #[test]
+    fn test_stack_underflow_01() {
+        // POP on an empty stack underflows immediately
+        let bs = blocks(&[POP,STOP],&JumpTable::new());
+        assert_eq!(bs[0].stack_underflow(),Some(0x0));
+        assert_eq!(bs[0].stack_delta(),-1);
+    }
+
+    #[test]
+    fn test_stack_underflow_02() {
+        // POP after a PUSH is fine
+        let bs = blocks(&[PUSH(vec![0x1]),POP,STOP],&JumpTable::new());
+        assert_eq!(bs[0].stack_underflow(),None);
+        assert_eq!(bs[0].stack_delta(),0);
+    }
+
+    #[test]
+    fn test_stack_underflow_03() {
+        // DUP and SWAP leave the items they reach intact
+        let bs = blocks(&[PUSH(vec![0x1]),DUP(1),SWAP(1),POP,POP,STOP],&JumpTable::new());
+        assert_eq!(bs[0].stack_underflow(),None);
+        assert_eq!(bs[0].stack_delta(),0);
+        assert_eq!(bs[0].required_operands(),0);
+    }
+
+    #[test]
+    fn test_writes_fmp_01() {
+        // Write to a huge offset does not touch the pointer
+        let bs = blocks(&[PUSH(vec![0x1]),PUSH(vec![0xff;8]),MSTORE,STOP],&JumpTable::new());
+        assert!(!bs[0].writes_fmp(2));
+        // Byte written just below the pointer does not either
+        let bs = blocks(&[PUSH(vec![0x1]),PUSH(vec![0x3f]),MSTORE8,STOP],&JumpTable::new());
+        assert!(!bs[0].writes_fmp(2));
+        // Byte written within the pointer does
+        let bs = blocks(&[PUSH(vec![0x1]),PUSH(vec![0x40]),MSTORE8,STOP],&JumpTable::new());
+        assert!(bs[0].writes_fmp(2));
     }
 }
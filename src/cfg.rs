@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use evmil::bytecode::{Assemble, Assembly,BlockVec, Instruction, StructuredSection};
 use evmil::analysis::{BlockGraph};
 use evmil::util::{dominators,SortedVec,transitive_closure};
-use crate::block::{Block,BlockSequence,PreconditionFn};
+use crate::block::{Block,BlockSequence,JumpTable,PreconditionFn};
 
 type DomSet = SortedVec<usize>;
 
@@ -24,17 +25,25 @@ pub struct ControlFlowGraph<'a> {
     /// The designated block decomposition.  Observe that,
     /// unfortunately, this decomposition may differ from the
     /// decompisition used in the graph.
-    blocks: BlockSequence
+    blocks: BlockSequence,
+    /// Memoized mapping from byte offsets to nodes in the underlying
+    /// graph.
+    nodes: HashMap<usize,usize>,
+    /// Memoized mapping from block offsets to their owning root (if
+    /// any).  This is only available once the roots are finalised.
+    owners: Option<HashMap<usize,usize>>,
+    /// Signals whether or not graph construction was incomplete.
+    incomplete: bool
 }
 
 impl<'a> ControlFlowGraph<'a> {
-    pub fn new(cid: usize, blocksize: usize, insns: &'a [Instruction], precheck: PreconditionFn, limit: usize) -> Self {
+    pub fn new(cid: usize, blocksize: usize, insns: &'a [Instruction], prechecks: &[PreconditionFn], jumps: &JumpTable, limit: usize, merge: bool, inline: bool) -> Self {
         // Construct graph
-        let graph = match BlockGraph::from_blocks(BlockVec::new(insns),limit) {
-	    Ok(graph) => graph,
+        let (graph,incomplete) = match BlockGraph::from_blocks(BlockVec::new(insns),limit) {
+	    Ok(graph) => (graph,false),
 	    Err(graph) => {
 		println!("WARNING: control-flow graph construction was incomplete");
-		graph
+		(graph,true)
 	    }
 	};
         // Compute dominators
@@ -42,15 +51,23 @@ impl<'a> ControlFlowGraph<'a> {
         // Compute transitive closure
         let reaches = transitive_closure(&graph);
         // Determine block decomposition based on the given block size.
-        let blocks = BlockSequence::from_insns(blocksize,insns,precheck,limit);        
+        let blocks = BlockSequence::from_insns(blocksize,insns,prechecks,jumps,limit,merge,inline);
+        // Memoize node lookups
+        let nodes = blocks.iter().map(|b| (b.pc(),graph.nodes().lookup_pc(b.pc()))).collect();
         // Done
-        Self{cid,graph,dominators,reaches,blocks, roots: Vec::new()}
+        Self{cid,graph,dominators,reaches,blocks,nodes,owners: None,roots: Vec::new(), incomplete}
     }
 
     pub fn cid(&self) -> usize {
         self.cid
     }
     
+    /// Check whether construction of this graph was incomplete, in
+    /// which case the information it contains may be imprecise.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete
+    }
+
     pub fn roots(&self) -> &[usize] {
         &self.roots
     }
@@ -62,13 +79,29 @@ impl<'a> ControlFlowGraph<'a> {
     /// Check whether a given root reaches another in one step
     /// (i.e. touches).
     pub fn touches(&self, from: usize, to: usize) -> bool {
-        let f = self.graph.nodes().lookup_pc(from);
-        let t = self.graph.nodes().lookup_pc(to);
+        let f = self.node(from);
+        let t = self.node(to);
         self.graph.outgoing(f).contains(t)
     }
     
     pub fn add_root(&mut self, pc: usize) {
         self.roots.push(pc);
+        // Invalidate ownership table
+        self.owners = None;
+    }
+
+    /// Compute the ownership table for the current set of roots.
+    /// This should be called once all roots have been added, after
+    /// which ownership queries no longer need to consider every root.
+    pub fn finalise_roots(&mut self) {
+        let mut owners = HashMap::new();
+        for blk in self.blocks.iter() {
+            // Observe at most one root can own a given block.
+            if let Some(r) = self.roots.iter().find(|r| self.compute_owns(**r,blk)) {
+                owners.insert(blk.pc(),*r);
+            }
+        }
+        self.owners = Some(owners);
     }
 
     /// Get the set of owned blocks for a given root (i.e. absolute
@@ -91,6 +124,15 @@ impl<'a> ControlFlowGraph<'a> {
     /// roots are absolute byte offset within the original bytecode
     /// sequence.
     pub fn owns(&self, root: usize, blk: &Block) -> bool {
+        match &self.owners {
+            Some(owners) => owners.get(&blk.pc()) == Some(&root),
+            None => self.compute_owns(root,blk)
+        }
+    }
+
+    /// Determine whether a given `root` owns a given block `blk`,
+    /// without using the ownership table.
+    fn compute_owns(&self, root: usize, blk: &Block) -> bool {
         // Dominator check
         if self.dominates(root,blk.pc()) {
             // Internal owner checker
@@ -108,8 +150,8 @@ impl<'a> ControlFlowGraph<'a> {
 
     /// Check whether a given bytecode offset dominates another.
     pub fn dominates(&self, parent: usize, child: usize) -> bool {
-        let gp = self.graph.nodes().lookup_pc(parent);
-        let gc = self.graph.nodes().lookup_pc(child);
+        let gp = self.node(parent);
+        let gc = self.node(child);
         // Dominator check
         self.dominators[gc].contains(gp)
     }
@@ -117,14 +159,196 @@ impl<'a> ControlFlowGraph<'a> {
     /// Check whether a given node can reach another through one or
     /// more steps.
     pub fn reaches(&self, parent: usize, child: usize) -> bool {        
-        let gp = self.graph.nodes().lookup_pc(parent);
-        let gc = self.graph.nodes().lookup_pc(child);
+        let gp = self.node(parent);
+        let gc = self.node(child);
         // Reachability check
         parent == child || self.reaches[gp].contains(gc)
     }
 
+    /// Determine the innermost roots which dominate a given block
+    /// offset.  That is, those dominating roots which do not dominate
+    /// any other dominating root.
+    pub fn dominating_roots(&self, pc: usize) -> Vec<usize> {
+        let doms : Vec<usize> = self.roots.iter().copied().filter(|r| self.dominates(*r,pc)).collect();
+        let mut rs : Vec<usize> = doms.iter().copied().filter(|r| {
+            !doms.iter().any(|s| s != r && self.dominates(*r,*s))
+        }).collect();
+        rs.sort_unstable();
+        rs
+    }
+
+    /// Determine the roots which can reach a given block offset,
+    /// but do not dominate it.
+    pub fn sharing_roots(&self, pc: usize) -> Vec<usize> {
+        let mut rs : Vec<usize> = self.roots.iter().copied().filter(|r| {
+            self.reaches(*r,pc) && !self.dominates(*r,pc)
+        }).collect();
+        rs.sort_unstable();
+        rs
+    }
+
+    /// Determine the loop headers of this graph.  That is, blocks
+    /// which are the target of a back-edge (i.e. an edge to a
+    /// dominator).
+    pub fn loop_headers(&self) -> Vec<usize> {
+        let mut headers = Vec::new();
+        for blk in self.blocks.iter() {
+            for pc in blk.successors() {
+                if self.dominates(pc,blk.pc()) { headers.push(pc); }
+            }
+        }
+        headers.sort_unstable();
+        headers.dedup();
+        headers
+    }
+
+    /// Determine the blocks which participate in a loop.  That is,
+    /// blocks in a strongly connected component which contains a
+    /// cycle.
+    pub fn loop_blocks(&self) -> Vec<usize> {
+        let mut blocks = Vec::new();
+        for scc in self.sccs() {
+            let cyclic = scc.len() > 1 || self.blocks.iter().any(|b| b.pc() == scc[0] && b.successors().contains(&scc[0]));
+            if cyclic { blocks.extend(scc); }
+        }
+        blocks.sort_unstable();
+        blocks
+    }
+
+    /// Determine the strongly connected components of this graph
+    /// using Tarjan's algorithm.  Each component is given as the set
+    /// of block offsets it contains.
+    pub fn sccs(&self) -> Vec<Vec<usize>> {
+        sccs(self.blocks.as_ref())
+    }
+
+    /// Remove all blocks which are not reachable from a given entry
+    /// point (i.e. absolute byte offset).
+    pub fn prune(&mut self, entry: usize) {
+        let reachable : Vec<bool> = self.blocks.iter().map(|b| self.reaches(entry,b.pc())).collect();
+        let mut i = 0;
+        self.blocks.retain(|_| { i += 1; reachable[i-1] });
+    }
+
+    /// Determine the node in the underlying graph for a given byte
+    /// offset, using the memoized mapping where possible.
+    fn node(&self, pc: usize) -> usize {
+        match self.nodes.get(&pc) {
+            Some(n) => *n,
+            None => self.graph.nodes().lookup_pc(pc)
+        }
+    }
+
+    /// Replace identical blocks by a single shared block, except for
+    /// those starting at one of the given byte offsets.
+    pub fn dedup(&mut self, keep: &[usize]) {
+        self.blocks.dedup(keep);
+        let blocks = &self.blocks;
+        self.nodes.retain(|pc,_| blocks.iter().any(|b| b.pc() == *pc));
+    }
+
     /// Minimise the information retained in this control-flow graph.
     pub fn minimise(&mut self) {
         self.blocks.minimise()
     }
 }
+
+/// Determine the strongly connected components of a given sequence
+/// of blocks using Tarjan's algorithm.  Each component is given as
+/// the set of block offsets it contains, and components are produced
+/// in reverse topological order (i.e. a component comes after all
+/// those it reaches).  Edges leaving the sequence are ignored.
+pub fn sccs(blocks: &[Block]) -> Vec<Vec<usize>> {
+    let offsets : HashMap<usize,usize> = blocks.iter().enumerate().map(|(i,b)| (b.pc(),i)).collect();
+    let mut tarjan = Tarjan{
+        index: 0,
+        indices: vec![None; blocks.len()],
+        lowlinks: vec![0; blocks.len()],
+        onstack: vec![false; blocks.len()],
+        stack: Vec::new(),
+        sccs: Vec::new()
+    };
+    for i in 0..blocks.len() {
+        if tarjan.indices[i].is_none() {
+            tarjan.visit(i,blocks,&offsets);
+        }
+    }
+    tarjan.sccs
+}
+
+/// State used in computing strongly connected components.
+struct Tarjan {
+    index: usize,
+    indices: Vec<Option<usize>>,
+    lowlinks: Vec<usize>,
+    onstack: Vec<bool>,
+    stack: Vec<usize>,
+    sccs: Vec<Vec<usize>>
+}
+
+impl Tarjan {
+    fn visit(&mut self, i: usize, blocks: &[Block], offsets: &HashMap<usize,usize>) {
+        self.indices[i] = Some(self.index);
+        self.lowlinks[i] = self.index;
+        self.index += 1;
+        self.stack.push(i);
+        self.onstack[i] = true;
+        // Consider successors
+        for pc in blocks[i].successors() {
+            let j = match offsets.get(&pc) { Some(j) => *j, None => { continue; } };
+            match self.indices[j] {
+                None => {
+                    self.visit(j,blocks,offsets);
+                    self.lowlinks[i] = self.lowlinks[i].min(self.lowlinks[j]);
+                }
+                Some(idx) if self.onstack[j] => {
+                    self.lowlinks[i] = self.lowlinks[i].min(idx);
+                }
+                _ => {}
+            }
+        }
+        // Check whether root of component
+        if Some(self.lowlinks[i]) == self.indices[i] {
+            let mut scc = Vec::new();
+            loop {
+                let j = self.stack.pop().unwrap();
+                self.onstack[j] = false;
+                scc.push(blocks[j].pc());
+                if i == j { break; }
+            }
+            scc.sort_unstable();
+            self.sccs.push(scc);
+        }
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evmil::bytecode::Instruction::*;
+
+    #[test]
+    fn test_owners_01() {
+        // A chain of 500 blocks, with a root every 50 blocks
+        let mut insns = vec![JUMPDEST; 500];
+        insns.push(STOP);
+        let mut cfg = ControlFlowGraph::new(0,usize::MAX,&insns,&[],&JumpTable::new(),usize::MAX,false,false);
+        let roots : Vec<usize> = (0..500).step_by(50).collect();
+        for r in &roots { cfg.add_root(*r); }
+        // Ownership computed without the table
+        let expected : Vec<Vec<usize>> = roots.iter().map(|r| {
+            cfg.blocks().iter().filter(|b| cfg.compute_owns(*r,b)).map(|b| b.pc()).collect()
+        }).collect();
+        cfg.finalise_roots();
+        for (r,owned) in roots.iter().zip(&expected) {
+            let actual : Vec<usize> = cfg.get_owned(*r).iter().map(|b| b.pc()).collect();
+            assert_eq!(&actual,owned);
+            // Each root owns the blocks up to the next root
+            assert_eq!(actual.len(),50);
+        }
+    }
+}
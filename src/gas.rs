@@ -0,0 +1,37 @@
+use evmil::bytecode::Instruction;
+use evmil::bytecode::Instruction::*;
+
+// =============================================================================
+// Static Gas Costs
+// =============================================================================
+
+/// Determine the static gas cost of a given instruction, or `None` if
+/// its cost has a dynamic component (e.g. memory expansion, storage
+/// access or calls).  For such instructions, no lower bound is given
+/// since the static component alone can be misleading.
+pub fn static_cost(insn: &Instruction) -> Option<u64> {
+    let cost = match insn {
+        STOP => 0,
+        // Tier: very low
+        ADD|SUB|LT|GT|SLT|SGT|EQ|ISZERO|AND|OR|XOR|NOT|BYTE|SHL|SHR|SAR => 3,
+        CALLDATALOAD|PUSH(_)|DUP(_)|SWAP(_) => 3,
+        // Tier: low
+        MUL|DIV|SDIV|MOD|SMOD|SIGNEXTEND|SELFBALANCE => 5,
+        // Tier: mid
+        ADDMOD|MULMOD|JUMP => 8,
+        // Tier: high
+        JUMPI => 10,
+        // Tier: base
+        ADDRESS|ORIGIN|CALLER|CALLVALUE|CALLDATASIZE|CODESIZE|GASPRICE => 2,
+        RETURNDATASIZE|COINBASE|TIMESTAMP|NUMBER|DIFFICULTY|GASLIMIT|CHAINID => 2,
+        POP|PC|MSIZE|GAS|PUSH0 => 2,
+        JUMPDEST => 1,
+        BLOCKHASH => 20,
+        TLOAD|TSTORE => 100,
+        // Virtual instructions
+        HAVOC(_)|DATA(_) => 0,
+        // Everything else has a dynamic component
+        _ => { return None; }
+    };
+    Some(cost)
+}
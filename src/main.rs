@@ -1,138 +1,780 @@
 mod analysis;
 mod block;
 mod cfg;
+mod gas;
 mod opcodes;
 mod printer;
+mod refine;
 
 use std::env;
 use std::fs;
-use std::fs::File;
 use std::path::Path;
-use std::io::{BufWriter,Write};
+use std::io::{Read,Write};
 use std::collections::HashMap;
 use std::error::Error;
-use clap::{Arg, Command};
-use serde::Deserialize;
+use std::sync::Mutex;
+use std::str::FromStr;
+use clap::{Arg, ArgMatches, Command};
+use rayon::prelude::*;
+use serde::{Deserialize,Serialize};
 use evmil::analysis::{BlockGraph,insert_havocs,trace};
 use evmil::bytecode::{Assemble, Assembly, Instruction, StructuredSection};
 use evmil::bytecode::Instruction::*;
 use evmil::util::{dominators,FromHexString,SortedVec,ToHexString};
 use analysis::{State};
-use block::{Block,BlockSequence,Bytecode,PreconditionFn};
+use block::{Block,BlockSequence,Bytecode,JumpTable,PreconditionFn,scope_necessary_stateinfo};
 use cfg::ControlFlowGraph;
 use printer::*;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    //let args: Vec<String> = env::args().collect();
-    let matches = Command::new("devmpg")
+    let matches = cli().get_matches();
+    let settings = configure(&matches)?;
+    // Check whether processing one target, or many.
+    match matches.get_one::<String>("targets-file") {
+        Some(targets_file) => {
+            let cwd = env::current_dir()?;
+            let outdir = settings.outdir.clone().unwrap_or(".".to_string());
+            //
+            for line in fs::read_to_string(targets_file)?.lines() {
+                let target = line.trim();
+                if target.is_empty() { continue; }
+                // Each target is written into its own subdirectory
+                let mut tsettings = settings.clone();
+                tsettings.prefix = default_prefix(target);
+                let dir = Path::new(&outdir).join(&tsettings.prefix);
+                tsettings.outdir = Some(dir.to_string_lossy().to_string());
+                // Paths are relative to the original directory
+                env::set_current_dir(&cwd)?;
+                run(target,&tsettings)?;
+            }
+        }
+        None => {
+            let target = matches.get_one::<String>("target").unwrap();
+            run(target,&settings)?;
+        }
+    }
+    // Done
+    Ok(())
+}
+
+/// Construct the command-line interface.
+fn cli() -> Command<'static> {
+    Command::new("devmpg")
         .about("DafnyEvm Proof Generation Tool")
         .arg(Arg::new("overflow").long("overflows"))        
+        .arg(Arg::new("shift-checks").long("shift-checks"))
+        .arg(Arg::new("div-checks").long("div-checks"))
         .arg(Arg::new("blocksize")
              .long("blocksize")
              .value_name("SIZE")
              .value_parser(clap::value_parser!(usize))
              .default_value("65535"))
+        .arg(Arg::new("merge-straightline").long("merge-straightline"))
+        .arg(Arg::new("inline-fallthrough").long("inline-fallthrough"))
+        .arg(Arg::new("mask-hints").long("mask-hints"))
+        .arg(Arg::new("dedup").long("dedup"))
         .arg(Arg::new("outdir").long("outdir").short('o').value_name("DIR"))
         .arg(Arg::new("devmdir").long("devmdir").value_name("DIR").default_value("evm-dafny"))
         .arg(Arg::new("debug").long("debug"))	
         .arg(Arg::new("minimise").long("minimise"))
         .arg(Arg::new("minimise-all").long("minimise-all"))
+        .arg(Arg::new("minimise-memory").long("minimise-memory"))
+        .arg(Arg::new("minimise-per-group").long("minimise-per-group"))
 	.arg(Arg::new("masks").long("masks"))
         .arg(Arg::new("split").long("split").value_name("json-file"))
-        .arg(Arg::new("target").required(true))
+        .arg(Arg::new("detect-roots").long("detect-roots"))
+        .arg(Arg::new("file-per-block").long("file-per-block"))
+        .arg(Arg::new("keep-unreachable-comments").long("keep-unreachable-comments"))
+        .arg(Arg::new("fmp-stable").long("fmp-stable"))
+        .arg(Arg::new("no-havoc-comments").long("no-havoc-comments"))
+        .arg(Arg::new("jump-targets").long("jump-targets").value_name("json-file"))
+        .arg(Arg::new("compact").long("compact"))
+        .arg(Arg::new("opaque-bytecode").long("opaque-bytecode"))
+        .arg(Arg::new("precompile-model").long("precompile-model").value_name("json-file"))
+        .arg(Arg::new("entry-pc").long("entry-pc").value_name("HEX"))
+        .arg(Arg::new("deterministic-havoc").long("deterministic-havoc"))
+        .arg(Arg::new("summary-json").long("summary-json").value_name("FILE"))
+        .arg(Arg::new("json").long("json").value_name("FILE"))
+        .arg(Arg::new("check-jumpdests").long("check-jumpdests"))
+        .arg(Arg::new("jumpdest-requires").long("jumpdest-requires"))
+        .arg(Arg::new("report-deadcode").long("report-deadcode"))
+        .arg(Arg::new("dry-run").long("dry-run"))
+        .arg(Arg::new("verify").long("verify"))
+        .arg(Arg::new("dafny").long("dafny").value_name("BIN").default_value("dafny"))
+        .arg(Arg::new("check-assemble").long("check-assemble"))
+        .arg(Arg::new("include-guard").long("include-guard"))
+        .arg(Arg::new("isolate-assertions").long("isolate-assertions"))
+        .arg(Arg::new("asm").long("asm"))
+        .arg(Arg::new("returndata-requires").long("returndata-requires"))
+        .arg(Arg::new("no-std-requires").long("no-std-requires"))
+        .arg(Arg::new("strict-assemble").long("strict-assemble"))
+        .arg(Arg::new("functional").long("functional"))
+        .arg(Arg::new("binary").long("binary"))
+        .arg(Arg::new("prefix").long("prefix").value_name("PREFIX"))
+        .arg(Arg::new("decreases").long("decreases").value_name("MEASURE"))
+        .arg(Arg::new("single-file").long("single-file"))
+        .arg(Arg::new("pc-comments").long("pc-comments"))
+        .arg(Arg::new("reachability-lemma").long("reachability-lemma"))
+        .arg(Arg::new("external-call").long("external-call").value_name("FILE"))
+        .arg(Arg::new("no-external-call").long("no-external-call"))
+        .arg(Arg::new("gas-bounds").long("gas-bounds"))
+        .arg(Arg::new("indent-spaces")
+             .long("indent-spaces")
+             .value_name("N")
+             .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("base-level")
+             .long("base-level")
+             .value_name("N")
+             .value_parser(clap::value_parser!(usize))
+             .default_value("1"))
+        .arg(Arg::new("diff-against").long("diff-against").value_name("DIR"))
+        .arg(Arg::new("state-var").long("state-var").value_name("NAME").default_value("st"))
+        .arg(Arg::new("split-output-size")
+             .long("split-output-size")
+             .value_name("BYTES")
+             .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("max-methods-per-file")
+             .long("max-methods-per-file")
+             .value_name("N")
+             .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("isolate-assertions-map").long("isolate-assertions-map").value_name("json-file"))
+        .arg(Arg::new("group-by")
+             .long("group-by")
+             .value_name("STRATEGY")
+             .value_parser(["function","scc","single"])
+             .default_value("function"))
+        .arg(Arg::new("targets-file").long("targets-file").value_name("FILE"))
+        .arg(Arg::new("target").required_unless_present("targets-file"))
         .arg(Arg::new("limit")
              .long("limit")
              .value_name("LIMIT")
              .value_parser(clap::value_parser!(usize))
              .default_value("4294967296"))	
-        .get_matches();
-    // Extract arguments
-    let target = matches.get_one::<String>("target").unwrap();   
-    // Configure settings
-    let settings = Config{
+}
+
+/// Determine the settings (including roots) for a given set of
+/// command-line arguments.
+fn configure(matches: &ArgMatches) -> Result<Config, Box<dyn Error>> {
+    // Remaining gas never decreases, since the generated code does
+    // not charge gas.
+    if matches.get_one::<String>("decreases").map(|m| m.as_str()) == Some("gas") {
+        return Err("--decreases gas is not supported (generated code does not charge gas)".into());
+    }
+    // Files are named after the target (unless otherwise specified)
+    let prefix = match (matches.get_one::<String>("prefix"),matches.get_one::<String>("target")) {
+        (Some(prefix),_) => prefix.clone(),
+        (None,Some(target)) if target != "-" => default_prefix(target),
+        (None,_) => DEFAULT_PREFIX.to_string()
+    };
+    let mut settings = Config{
 	outdir: matches.get_one("outdir").map(|s: &String| s.clone()),
 	devmdir: matches.get_one::<String>("devmdir").unwrap().clone(),
-	prefix: default_prefix(target),
-	checks: overflow_checks, // for now
+	prefix,
+	roots: HashMap::new(),
+	checks: Vec::new(),
 	blocksize: *matches.get_one("blocksize").unwrap(),
+	merge_straightline: matches.is_present("merge-straightline"),
+	inline_fallthrough: matches.is_present("inline-fallthrough"),
+	mask_hints: matches.is_present("mask-hints"),
+	dedup: matches.is_present("dedup"),
 	limit: *matches.get_one("limit").unwrap(),
 	debug: matches.is_present("debug"),
 	masks: matches.is_present("masks"),
 	minimise_requires: matches.is_present("minimise")||matches.is_present("minimise-all"),
 	minimise_internal: matches.is_present("minimise-all"),
+	minimise_memory: matches.is_present("minimise-memory"),
+	minimise_per_group: matches.is_present("minimise-per-group"),
+	file_per_block: matches.is_present("file-per-block"),
+	unreachable_comments: matches.is_present("keep-unreachable-comments"),
+	fmp_stable: matches.is_present("fmp-stable"),
+	no_havoc_comments: matches.is_present("no-havoc-comments"),
+	jump_targets: JumpTable::new(),
+	compact: matches.is_present("compact"),
+	group_by: matches.get_one::<String>("group-by").unwrap().parse()?,
+	opaque_bytecode: matches.is_present("opaque-bytecode"),
+	precompiles: HashMap::new(),
+	entry_pc: matches.get_one::<String>("entry-pc").map(|s| from_hex(s)).transpose()?,
+	deterministic_havoc: matches.is_present("deterministic-havoc"),
+	summary_json: matches.get_one("summary-json").map(|s: &String| s.clone()),
+	analysis_json: matches.get_one("json").map(|s: &String| s.clone()),
+	check_jumpdests: matches.is_present("check-jumpdests"),
+	jumpdest_requires: matches.is_present("jumpdest-requires"),
+	report_deadcode: matches.is_present("report-deadcode"),
+	dry_run: matches.is_present("dry-run"),
+	verify: matches.is_present("verify"),
+	dafny: matches.get_one::<String>("dafny").unwrap().clone(),
+	check_assemble: matches.is_present("check-assemble"),
+	include_guard: matches.is_present("include-guard"),
+	isolate_assertions: matches.is_present("isolate-assertions"),
+	isolate_overrides: HashMap::new(),
+	asm: matches.is_present("asm"),
+	returndata_requires: matches.is_present("returndata-requires"),
+	split_output_size: matches.get_one("split-output-size").copied(),
+	max_methods_per_file: matches.get_one("max-methods-per-file").copied(),
+	calldata_bounds: HashMap::new(),
+	state_var: matches.get_one::<String>("state-var").unwrap().clone(),
+	no_std_requires: matches.is_present("no-std-requires"),
+	diff_against: matches.get_one("diff-against").map(|s: &String| s.clone()),
+	strict_assemble: matches.is_present("strict-assemble"),
+	functional: matches.is_present("functional"),
+	detect_roots: matches.is_present("detect-roots"),
+	binary: matches.is_present("binary"),
+	decreases: matches.get_one("decreases").map(|s: &String| s.clone()),
+	single_file: matches.is_present("single-file"),
+	indent: match matches.get_one::<usize>("indent-spaces") {
+	    Some(n) => " ".repeat(*n),
+	    None => "\t".to_string()
+	},
+	base_level: *matches.get_one("base-level").unwrap(),
+	pc_comments: matches.is_present("pc-comments"),
+	reachability_lemma: matches.is_present("reachability-lemma"),
+	external_call: matches.get_one::<String>("external-call").map(|f| fs::read_to_string(f)).transpose()?,
+	no_external_call: matches.is_present("no-external-call"),
+	gas_bounds: matches.is_present("gas-bounds")
     };
-    let overflows = matches.is_present("overflow");
-    // Read from asm file
-    let hex = fs::read_to_string(target)?;
-    let bytes = hex.trim().from_hex_string()?;    
-    // Setup configuration
-    let mut roots = HashMap::new();    
-    // Configure roots
-    roots.insert((0,0),"main".to_string());
+    // Append any precondition checks (in order)
+    if matches.is_present("overflow") { settings.checks.push(overflow_checks); }
+    if matches.is_present("shift-checks") { settings.checks.push(shift_checks); }
+    if matches.is_present("div-checks") { settings.checks.push(division_checks); }
     // Check if a config is provided
-    if matches.is_present("split") {
-        let split_filename = matches.get_one::<String>("split").unwrap();
+    if let Some(split_filename) = matches.get_one::<String>("split") {
         let split_file = fs::read_to_string(split_filename)?;        
         let cf: ConfigFile = serde_json::from_str(&split_file)?;
         //
-        for (n,hs) in cf.functions {
-            // Strip off leader
-            let ths = hs.trim_start_matches("0x");
-            let pc = usize::from_str_radix(ths,16)?;
-            roots.insert((0,pc),n);
+        // Functions given by offset alone belong to the first code section
+        let functions = match cf.functions {
+            Functions::Offsets(fs) => fs.into_iter().map(|(n,hs)| Ok((n,0,from_hex(&hs)?))).collect::<Result<Vec<_>,Box<dyn Error>>>()?,
+            Functions::Roots(fs) => fs.into_iter().map(|f| (f.name,f.cid,f.pc)).collect()
+        };
+        for (n,cid,pc) in functions {
+            // Determine minimum calldata size (if signature known)
+            if let Some(sig) = cf.signatures.get(&n) {
+                match abi_head_words(sig) {
+                    Some(w) if cid == 0 => { settings.calldata_bounds.insert(pc,4 + 32*w); }
+                    Some(_) => {}
+                    None => { println!("WARNING: ignoring malformed signature \"{sig}\""); }
+                }
+            }
+            settings.roots.insert((cid,pc),n);
         }
-    }    
-    // Disassemble bytes into instructions    
-    let mut contract = Assembly::from_legacy_bytes(&bytes);    
+    } else {
+        // Default root at the contract entry, used only when no roots
+        // are otherwise provided.
+        settings.roots.insert((0,0),"main".to_string());
+    }
+    // Check if a jump table is provided
+    if let Some(jumps_filename) = matches.get_one::<String>("jump-targets") {
+        let jumps_file = fs::read_to_string(jumps_filename)?;
+        let jt: HashMap<String,Vec<String>> = serde_json::from_str(&jumps_file)?;
+        //
+        for (pc,targets) in jt {
+            let targets = targets.iter().map(|t| from_hex(t)).collect::<Result<Vec<_>,_>>()?;
+            settings.jump_targets.insert(from_hex(&pc)?,targets);
+        }
+    }
+    // Check if an isolate assertions map is provided
+    if let Some(map_filename) = matches.get_one::<String>("isolate-assertions-map") {
+        let map_file = fs::read_to_string(map_filename)?;
+        let im: HashMap<String,bool> = serde_json::from_str(&map_file)?;
+        //
+        for (pc,isolate) in im {
+            settings.isolate_overrides.insert(from_hex(&pc)?,isolate);
+        }
+    }
+    // Check if a precompile model is provided
+    if let Some(model_filename) = matches.get_one::<String>("precompile-model") {
+        let model_file = fs::read_to_string(model_filename)?;
+        let pm: HashMap<String,PrecompileModel> = serde_json::from_str(&model_file)?;
+        //
+        for (addr,model) in pm {
+            settings.precompiles.insert(from_hex(&addr)?,model);
+        }
+    }
+    Ok(settings)
+}
+
+/// Generate proof files for a given target hex file, using the given
+/// settings.
+fn run(target: &str, settings: &Config) -> Result<(), Box<dyn Error>> {
+    let input = read_input(target,&mut std::io::stdin())?;
+    let (bytes,contract) = load(input,settings)?;
+    // Sanity check only (if applicable)
+    if settings.check_assemble {
+        return check_assemble(target,&bytes,&contract);
+    }
+    // Report planned files only (if applicable)
+    if settings.dry_run {
+        let groups = analyse(&contract,settings)?;
+        let files = render(&contract,&groups,settings)?;
+        for line in plan(&files,&groups) { println!("{line}"); }
+        return Ok(());
+    }
+    // Resolve previous output (if applicable) before changing directory
+    let previous = match &settings.diff_against {
+        Some(dir) => Some(fs::canonicalize(dir)?),
+        None => None
+    };
+    // Generate files
+    let files = generate(&contract,settings)?;
+    // Set output directory and write files
+    configure_outdir(&settings.outdir)?;
+    let mut filenames : Vec<&String> = files.keys().collect();
+    filenames.sort();
+    for filename in filenames {
+        println!("Writing {filename}");
+        fs::write(filename,&files[filename]).map_err(|e| format!("error writing {filename}: {e}"))?;
+    }
+    // Verify files (if applicable)
+    if settings.verify {
+        verify(&verification_targets(&files,settings),settings)?;
+    }
+    // Compare against previous output (if applicable)
+    if let Some(dir) = previous {
+        diff_against(&dir,&files,settings)?;
+    }
+    // Done
+    Ok(())
+}
+
+/// Read the raw input for a given target, where "-" denotes the given
+/// standard input.
+fn read_input(target: &str, stdin: &mut dyn Read) -> Result<Vec<u8>, Box<dyn Error>> {
+    if target == "-" {
+        let mut buf = Vec::new();
+        stdin.read_to_end(&mut buf)?;
+        Ok(buf)
+    } else {
+        Ok(fs::read(target)?)
+    }
+}
+
+/// Convert the raw input for a target into a contract, returning the
+/// original bytes alongside it.  Havoc instructions are inferred for
+/// the contract, ready for analysis.
+fn load(input: Vec<u8>, settings: &Config) -> Result<(Vec<u8>,Assembly), Box<dyn Error>> {
+    let (bytes,contract) = if settings.asm {
+        // Assemble text into instructions
+        let contract = Assembly::from_str(&String::from_utf8(input)?).map_err(|e| format!("{e:?}"))?;
+        (to_bytes(&contract),contract)
+    } else {
+        let bytes = if settings.binary {
+            input
+        } else {
+            String::from_utf8(input)?.trim().from_hex_string()?
+        };
+        // Disassemble bytes into instructions    
+        let contract = Assembly::from_legacy_bytes(&bytes);    
+        (bytes,contract)
+    };
+    // Check push encodings (if applicable)
+    if settings.strict_assemble {
+        check_push_encodings(&contract)?;
+    }
     // Infer havoc instructions
-    contract = infer_havoc_insns(contract,settings.limit);
+    let contract = infer_havoc_insns(contract,settings.limit,settings.deterministic_havoc);
+    Ok((bytes,contract))
+}
+
+/// Analyse a given contract into groups of blocks, using the given
+/// settings.
+fn analyse(contract: &Assembly, settings: &Config) -> Result<Vec<BlockGroup>, Box<dyn Error>> {
+    let mut roots = settings.roots.clone();
     // Deconstruct into sequences
-    let mut cfgs = deconstruct(&contract,&settings);
+    let mut cfgs = deconstruct(contract,settings);
+    // Detect function roots (if applicable), where those given
+    // explicitly take precedence.
+    if settings.detect_roots {
+        for (r,name) in detect_roots(&cfgs) {
+            roots.entry(r).or_insert(name);
+        }
+    }
+    // Override roots when starting from an arbitrary entry point
+    let roots = match settings.entry_pc {
+        Some(pc) => {
+            if !cfgs[0].blocks().iter().any(|b| b.pc() == pc) {
+                return Err(format!("entry pc {pc:#x} is not the start of a block").into());
+            }
+            cfgs[0].prune(pc);
+            HashMap::from([((0,pc),"main".to_string())])
+        }
+        None => roots
+    };
+    // Default root for any other code section without one
+    let mut roots = roots;
+    for cfg in &cfgs {
+        let c = cfg.cid();
+        if c != 0 && !roots.keys().any(|(d,_)| *d == c) {
+            roots.insert((c,0),format!("main_{c}"));
+        }
+    }
+    // Ignore roots which don't start a block
+    let roots : HashMap<_,_> = roots.into_iter().filter(|((c,pc),name)| {
+        let cfg = match cfgs.iter().find(|cfg| cfg.cid() == *c) {
+            Some(cfg) => cfg,
+            None => {
+                println!("WARNING: ignoring root {name} in section {c} (not a code section)");
+                return false;
+            }
+        };
+        let valid = cfg.blocks().iter().any(|b| b.pc() == *pc);
+        if !valid { println!("WARNING: ignoring root {name} at {pc:#x} (not the start of a block)"); }
+        valid
+    }).collect();
+    // Deduplicate blocks (if applicable), keeping roots addressable
+    if settings.dedup {
+        for cfg in &mut cfgs {
+            let keep : Vec<usize> = roots.keys().filter(|(c,_)| *c == cfg.cid()).map(|(_,pc)| *pc).collect();
+            cfg.dedup(&keep);
+        }
+    }
     // Configure roots
-    for (c,r) in roots.keys() {
-        cfgs[*c].add_root(*r);
+    for ((c,r),_) in &roots {
+        for cfg in cfgs.iter_mut().filter(|cfg| cfg.cid() == *c) { cfg.add_root(*r); }
+    }
+    for cfg in &mut cfgs {
+        cfg.finalise_roots();
+    }
+    // Report deadcode (if applicable)
+    if settings.report_deadcode {
+        report_deadcode(&cfgs);
     }
     // Group subsequences
-    let groups = group(roots,&cfgs);
-    // Set output directory
-    configure_outdir(&settings.outdir);    
-    write_headers(&contract,&settings);
-    // Write files
-    write_groups(groups,&settings);
-    // Done
+    let mut groups = group(roots,&cfgs,settings.group_by);
+    // Scope minimisation to each group (if applicable)
+    if settings.minimise_per_group {
+        for g in &mut groups { scope_necessary_stateinfo(&mut g.blocks); }
+    }
+    Ok(groups)
+}
+
+/// Generate proof files for a given contract, without touching the
+/// filesystem.  The result maps each filename to its contents, and
+/// includes any analysis results or summary requested.
+fn generate(contract: &Assembly, settings: &Config) -> Result<HashMap<String,String>, Box<dyn Error>> {
+    // Files are named by prefix, even when none was configured
+    if settings.prefix.is_empty() {
+        let settings = Config{prefix: DEFAULT_PREFIX.to_string(), ..settings.clone()};
+        return generate(contract,&settings);
+    }
+    // Analyse contract into groups
+    let groups = analyse(contract,settings)?;
+    render(contract,&groups,settings)
+}
+
+/// Generate proof files for a given contract which has already been
+/// analysed into groups, without touching the filesystem.
+fn render(contract: &Assembly, groups: &[BlockGroup], settings: &Config) -> Result<HashMap<String,String>, Box<dyn Error>> {
+    let files = Files::default();
+    // Write analysis results (if applicable)
+    if let Some(filename) = &settings.analysis_json {
+        write_analysis(&files,filename,groups)?;
+    }
+    if settings.single_file {
+        // Write everything into one file
+        write_single(&files,contract,groups,settings)?;
+    } else {
+        write_headers(&files,contract,settings)?;
+        if settings.file_per_block {
+            write_blocks(&files,groups,settings)?;
+        } else {
+            write_groups(&files,groups,settings)?;
+        }
+    }
+    // Write summary of what was generated (if applicable)
+    if let Some(filename) = &settings.summary_json {
+        let generated = files.filenames().into_iter().filter(|f| f.ends_with(".dfy")).collect();
+        write_summary(&files,filename,groups,generated,settings)?;
+    }
+    Ok(files.into_inner())
+}
+
+/// Determine which of the generated files should be verified.  When
+/// everything is included from a single top-level file, only that
+/// file can be verified.  Otherwise, every file except the headers is
+/// verified.
+fn verification_targets(files: &HashMap<String,String>, settings: &Config) -> Vec<String> {
+    if settings.single_file || settings.include_guard {
+        return vec![format!("{}.dfy",settings.prefix)];
+    }
+    let mut targets : Vec<String> = files.keys().filter(|f| f.ends_with(".dfy") && !f.ends_with("_header.dfy")).cloned().collect();
+    targets.sort();
+    targets
+}
+
+/// Compare the block methods just generated against those in a
+/// previous output directory, and report (as JSON) which parts of
+/// each block changed.
+fn diff_against(dir: &Path, files: &HashMap<String,String>, settings: &Config) -> Result<(), Box<dyn Error>> {
+    let prefix = &settings.prefix;
+    // Read previous output
+    let mut previous = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if name.starts_with(prefix.as_str()) && name.ends_with(".dfy") {
+            previous.insert(name,fs::read_to_string(&path)?);
+        }
+    }
+    let old = extract_methods(&previous,prefix);
+    let new = extract_methods(files,prefix);
+    let report = diff_methods(&old,&new);
+    println!("{}",serde_json::to_string_pretty(&report)?);
     Ok(())
 }
 
+/// Determine, for each block method which differs between two runs,
+/// whether it was added, removed or which parts of it changed.
+fn diff_methods(old: &HashMap<String,(String,String,String)>, new: &HashMap<String,(String,String,String)>) -> serde_json::Map<String,serde_json::Value> {
+    let mut report = serde_json::Map::new();
+    // Keys are sorted for stable output
+    let mut keys : Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for k in keys {
+        let status = match (old.get(k),new.get(k)) {
+            (None,_) => serde_json::json!("added"),
+            (_,None) => serde_json::json!("removed"),
+            (Some(o),Some(n)) if o == n => { continue; }
+            (Some(o),Some(n)) => serde_json::json!({
+                "signature": o.0 != n.0,
+                "requires": o.1 != n.1,
+                "body": o.2 != n.2
+            })
+        };
+        report.insert(k.clone(),status);
+    }
+    report
+}
+
+/// Extract the block methods (or functions) from all files with a
+/// given prefix.  Each method is identified by its name, and split
+/// into its signature, specification and body.
+fn extract_methods(files: &HashMap<String,String>, prefix: &str) -> HashMap<String,(String,String,String)> {
+    let mut methods = HashMap::new();
+    //
+    for (name,contents) in files {
+        if !name.starts_with(prefix) || !name.ends_with(".dfy") { continue; }
+        let mut current : Option<(String,(String,String,String))> = None;
+        for line in contents.lines() {
+            let trimmed = line.trim_start();
+            let header = trimmed.starts_with("method") || trimmed.starts_with("function");
+            if let Some(i) = trimmed.find("block_").filter(|_| header) {
+                if let Some((k,m)) = current.take() { methods.insert(k,m); }
+                let key = trimmed[i..].split('(').next().unwrap().to_string();
+                current = Some((key,(line.to_string(),String::new(),String::new())));
+            } else if let Some((_,m)) = &mut current {
+                if trimmed.starts_with("requires") || trimmed.starts_with("ensures") {
+                    m.1.push_str(line);
+                    m.1.push('\n');
+                } else if !trimmed.starts_with("//") {
+                    m.2.push_str(line);
+                    m.2.push('\n');
+                }
+            }
+        }
+        if let Some((k,m)) = current.take() { methods.insert(k,m); }
+    }
+    //
+    methods
+}
+
+/// Check that a disassembled contract reassembles to exactly the
+/// bytes it was disassembled from, reporting what was found.
+fn check_assemble(target: &str, bytes: &[u8], contract: &Assembly) -> Result<(), Box<dyn Error>> {
+    let nbytes = to_bytes(contract);
+    let mut count = 0;
+    //
+    for (i,s) in contract.iter().enumerate() {
+        match s {
+            StructuredSection::Code(insns) => {
+                count += insns.iter().filter(|insn| !matches!(insn,HAVOC(_))).count();
+            }
+            StructuredSection::Data(data) => {
+                println!("Data section {i} ({} bytes)",data.len());
+            }
+        }
+    }
+    println!("{target}: {count} instructions, {} bytes",bytes.len());
+    // Check round trip
+    if let Some(off) = (0..bytes.len().min(nbytes.len())).find(|i| bytes[*i] != nbytes[*i]) {
+        return Err(format!("round-trip mismatch at byte offset {off:#x}").into());
+    } else if bytes.len() != nbytes.len() {
+        return Err(format!("round-trip mismatch in length ({} vs {} bytes)",bytes.len(),nbytes.len()).into());
+    }
+    println!("{target}: round-trip ok");
+    Ok(())
+}
+
+/// Check that every push instruction uses the narrowest encoding for
+/// its operand.  That is, no push has an operand with leading zero
+/// bytes which a narrower push could encode.
+fn check_push_encodings(contract: &Assembly) -> Result<(), Box<dyn Error>> {
+    let mut count = 0;
+    //
+    for (i,s) in contract.iter().enumerate() {
+        if let StructuredSection::Code(insns) = s {
+            let mut pc = 0;
+            for insn in insns {
+                if let PUSH(bytes) = insn {
+                    if bytes.len() > 1 && bytes[0] == 0 {
+                        println!("WARNING: non-canonical PUSH{} at {pc:#06x} (section {i})",bytes.len());
+                        count += 1;
+                    }
+                }
+                pc += insn.length();
+            }
+        }
+    }
+    //
+    if count > 0 {
+        return Err(format!("{count} non-canonical push instruction(s)").into());
+    }
+    Ok(())
+}
+
+/// Assemble every section of a contract back into bytes.
+fn to_bytes(contract: &Assembly) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    //
+    for s in contract.iter() {
+        match s {
+            StructuredSection::Code(insns) => bytes.extend(insns.assemble()),
+            StructuredSection::Data(data) => bytes.extend(data)
+        }
+    }
+    //
+    bytes
+}
+
+/// Determine the number of words occupied by the head of the ABI
+/// encoding for the arguments of a given signature (e.g.
+/// `transfer(address,uint256)`).  Static types occupy their full size
+/// in the head, whilst dynamic types occupy a single (offset) word.
+/// This returns `None` if the signature is malformed.
+fn abi_head_words(sig: &str) -> Option<usize> {
+    let start = sig.find('(')?;
+    let params = sig[start..].strip_prefix('(')?.strip_suffix(')')?;
+    let mut words = 0;
+    for p in split_params(params)? {
+        words += abi_static_words(p)?.unwrap_or(1);
+    }
+    Some(words)
+}
+
+/// Determine the number of words occupied by a given ABI type, or
+/// `Some(None)` if the type is dynamic.
+fn abi_static_words(ty: &str) -> Option<Option<usize>> {
+    let ty = ty.trim();
+    if let Some(elem) = ty.strip_suffix(']') {
+        // Array type
+        let i = elem.rfind('[')?;
+        let len = &elem[i+1..];
+        return match abi_static_words(&elem[..i])? {
+            Some(w) if !len.is_empty() => Some(Some(w * len.parse::<usize>().ok()?)),
+            _ => Some(None)
+        };
+    } else if let Some(fields) = ty.strip_prefix('(') {
+        // Tuple type
+        let mut words = 0;
+        for f in split_params(fields.strip_suffix(')')?)? {
+            match abi_static_words(f)? {
+                Some(w) => { words += w; }
+                None => { return Some(None); }
+            }
+        }
+        return Some(Some(words));
+    }
+    match ty {
+        "bytes"|"string" => Some(None),
+        "address"|"bool"|"function" => Some(Some(1)),
+        _ if ty.starts_with("uint") || ty.starts_with("int") => Some(Some(1)),
+        _ if ty.starts_with("bytes") || ty.starts_with("fixed") || ty.starts_with("ufixed") => Some(Some(1)),
+        _ => None
+    }
+}
+
+/// Split a comma-separated parameter list at the top level (i.e. not
+/// within nested tuples).
+fn split_params(params: &str) -> Option<Vec<&str>> {
+    let mut ps = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    if params.trim().is_empty() { return Some(ps); }
+    for (i,c) in params.char_indices() {
+        match c {
+            '(' => { depth += 1; }
+            ')' if depth == 0 => { return None; }
+            ')' => { depth -= 1; }
+            ',' if depth == 0 => {
+                ps.push(&params[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ps.push(&params[start..]);
+    Some(ps)
+}
+
+/// Parse a hexadecimal string (with optional `0x` leader).
+fn from_hex(s: &str) -> Result<usize,std::num::ParseIntError> {
+    usize::from_str_radix(s.trim_start_matches("0x"),16)
+}
+
+/// Prefix used for generated files when none is given (e.g. when
+/// generating for a contract read from stdin).
+const DEFAULT_PREFIX : &str = "main";
+
 fn default_prefix(name: &str) -> String {
     let filename = Path::new(name).file_stem().unwrap().to_str().unwrap();
     filename.replace(".","_")
 }
 
-fn configure_outdir(outdir: &Option<String>) {
+fn configure_outdir(outdir: &Option<String>) -> Result<(), Box<dyn Error>> {
     // Create output directory
     match outdir {
         None => {}
         Some(d) => {
-            fs::create_dir_all(d);
-            env::set_current_dir(d);            
+            fs::create_dir_all(d).map_err(|e| format!("error creating {d}: {e}"))?;
+            env::set_current_dir(d).map_err(|e| format!("error entering {d}: {e}"))?;
         }
     };
+    Ok(())
 }
 
 #[derive(Clone,Debug)]
 struct Config {
     /// Prefix to use when generating files.
     prefix: String,
+    /// Named roots (e.g. public functions) from which to group blocks,
+    /// identified by code section and offset.
+    roots: HashMap<(usize,usize),String>,
     /// Determines where generated files should be placed.
     outdir: Option<String>,
     /// Identifies the path to the `evm-dafny` repository, so that can
     /// be included directly.
     devmdir: String,
-    /// Determines what checks should be applied to the disassembled bytecode.
-    checks: PreconditionFn,
+    /// Determines what checks should be applied to the disassembled
+    /// bytecode.  These are applied in order before each instruction.
+    checks: Vec<PreconditionFn>,
     /// Determines a limit on how many bytecodes to include in each
-    /// distinct block.
+    /// distinct block, where zero means no limit (i.e. blocks are only
+    /// split at control-flow boundaries).
     blocksize: usize,
+    /// Signals whether blocks split only because of the block size
+    /// should be merged back together (i.e. the block size is a soft
+    /// limit).
+    merge_straightline: bool,
+    /// Signals whether blocks which fall through into a `JUMPDEST`
+    /// should include a copy of the following block, rather than
+    /// calling it.
+    inline_fallthrough: bool,
+    /// Signals whether or not to assert the bound on the result of
+    /// each mask, as a hint for Dafny.
+    mask_hints: bool,
+    /// Signals whether or not identical blocks should share a single
+    /// method.  Blocks starting a root are never shared.
+    dedup: bool,
     /// Limits used to prevent non-termination.
     limit: usize,
     /// Signals whether or not to generate debug information around
@@ -146,7 +788,142 @@ struct Config {
     /// Signals whether or not to minimise the internal stack/memory
     /// information reported as comments.
     minimise_internal: bool,
-    
+    /// Signals whether or not to minimise memory information (e.g.
+    /// the free memory pointer) in `requires` clauses, independently
+    /// of the stack.
+    minimise_memory: bool,
+    /// Signals whether or not minimisation should consider only the
+    /// blocks of each group, rather than the whole code section.
+    minimise_per_group: bool,
+    /// Signals whether or not to write each block into its own file,
+    /// rather than one file per group.
+    file_per_block: bool,
+    /// Signals whether or not to explain why a block is deadcode.
+    unreachable_comments: bool,
+    /// Signals whether or not to assert that repeated reads of the
+    /// free memory pointer within a block yield the same value.
+    fmp_stable: bool,
+    /// Signals whether or not to omit comments marking havoc
+    /// instructions.
+    no_havoc_comments: bool,
+    /// Explicit jump targets to assume for jumps whose targets cannot
+    /// be determined by the analysis.
+    jump_targets: JumpTable,
+    /// Signals whether or not to omit all non-essential comments
+    /// (e.g. debug information, section headings, havocs, etc).
+    compact: bool,
+    /// Determines the strategy used for grouping blocks into files.
+    group_by: GroupBy,
+    /// Signals whether or not to make the bytecode constant opaque,
+    /// such that it is only revealed in blocks which need it.  This
+    /// avoids Dafny repeatedly examining the (large) constant.
+    opaque_bytecode: bool,
+    /// Models of precompiled contracts, indexed by address.
+    precompiles: HashMap<usize,PrecompileModel>,
+    /// Optional offset (within the first code section) from which to
+    /// generate, instead of from the contract's entry.
+    entry_pc: Option<usize>,
+    /// Canonicalise inserted havoc instructions, so that generated
+    /// output is reproducible.
+    deterministic_havoc: bool,
+    /// Optional file (within the output directory) to which a
+    /// machine-readable summary of the generated files is written.
+    summary_json: Option<String>,
+    /// Identifies the file (if any) to which a machine-readable
+    /// summary of the analysis results is written.
+    analysis_json: Option<String>,
+    /// Signals whether or not to assert that static jump targets hold
+    /// a `JUMPDEST` byte in the bytecode.
+    check_jumpdests: bool,
+    /// Signals whether or not blocks beginning with a `JUMPDEST`
+    /// should require they are valid jump destinations, rather than
+    /// callers assuming this before jumping to them.
+    jumpdest_requires: bool,
+    /// Signals whether or not to report all unreachable (i.e.
+    /// deadcode) blocks found by the analysis.
+    report_deadcode: bool,
+    /// Signals to report the files which would be generated, without
+    /// actually writing anything.
+    dry_run: bool,
+    /// Signals whether or not to verify the generated files using
+    /// Dafny.
+    verify: bool,
+    /// Path to the Dafny executable used for verification.
+    dafny: String,
+    /// Signals to only check the target can be disassembled and
+    /// reassembled losslessly, without writing any files.
+    check_assemble: bool,
+    /// Signals that generated files should be included only via a
+    /// single top-level file, rather than including each other.
+    include_guard: bool,
+    /// Signals whether or not block methods should be verified with
+    /// each assertion isolated (i.e. `{:isolate_assertions}`).
+    isolate_assertions: bool,
+    /// Overrides the above for blocks at specific offsets.
+    isolate_overrides: HashMap<usize,bool>,
+    /// Signals that targets are textual assembly files, rather than
+    /// hex strings.
+    asm: bool,
+    /// Signals whether or not to require there is no pending return
+    /// data on entry to blocks which cannot follow a call.
+    returndata_requires: bool,
+    /// Optional cap (in bytes) on the size of each generated group
+    /// file, beyond which a group is split across several files.
+    split_output_size: Option<usize>,
+    /// Optional cap on the number of block methods in each generated
+    /// group file, beyond which a group is split across several files.
+    max_methods_per_file: Option<usize>,
+    /// Minimum calldata size on entry to function roots (within the
+    /// first code section) whose ABI signatures are known.
+    calldata_bounds: HashMap<usize,usize>,
+    /// Name of the working state variable in generated block methods.
+    state_var: String,
+    /// Signals that no `requires` (or `ensures`) clauses should be
+    /// generated for block methods.
+    no_std_requires: bool,
+    /// Optional directory containing previously generated output, to
+    /// compare freshly generated blocks against.
+    diff_against: Option<String>,
+    /// Signals that push instructions must use the narrowest possible
+    /// encoding.
+    strict_assemble: bool,
+    /// Signals that blocks should be generated as Dafny functions
+    /// (i.e. chained expressions), rather than as methods.
+    functional: bool,
+    /// Signals that function roots should be detected from the
+    /// dispatcher.  Roots given explicitly (e.g. via `--split`) take
+    /// precedence.
+    detect_roots: bool,
+    /// Signals that the input is raw bytes, rather than a hex string.
+    binary: bool,
+    /// Optional termination measure for blocks which participate in
+    /// loops.  Observe that the remaining gas is not a suitable
+    /// measure (and is rejected), since the generated code does not
+    /// charge gas.
+    decreases: Option<String>,
+    /// Signals that all output should be written into a single file.
+    single_file: bool,
+    /// The string used for each level of indentation within block
+    /// methods.
+    indent: String,
+    /// The level of indentation for block methods (i.e. within their
+    /// enclosing module).
+    base_level: usize,
+    /// Signals that each bytecode should be preceded by a comment
+    /// giving its original offset and instruction.
+    pc_comments: bool,
+    /// Signals that each group module should end with a lemma stub
+    /// enumerating the reachability obligations of its blocks.
+    reachability_lemma: bool,
+    /// Optional body for the model of an external call, replacing the
+    /// dummy implementation.
+    external_call: Option<String>,
+    /// Signals that no model of an external call should be generated
+    /// (i.e. because one is provided elsewhere).
+    no_external_call: bool,
+    /// Signals that block methods should note their minimum gas
+    /// consumption, based on static gas costs.
+    gas_bounds: bool
 }
 
 #[derive(Debug, Deserialize)]
@@ -162,16 +939,50 @@ struct PublicFunction {
     pc: usize 
 }
 
+/// Describes what is known following a successful call to a given
+/// precompile.
+#[derive(Clone, Debug, Deserialize)]
+struct PrecompileModel {
+    /// Human-readable name of the precompile (e.g. `ecrecover`).
+    name: String,
+    /// Size (in bytes) of the data returned (if fixed).
+    returndatasize: Option<usize>,
+    /// Additional facts (as Dafny expressions over `st`, which is
+    /// replaced by the current state variable) which hold after the
+    /// call.
+    #[serde(default)]
+    facts: Vec<String>
+}
+
+/// Describes the contents of a split file (i.e. as given by
+/// `--split`).
 #[derive(Debug, Deserialize)]
 struct ConfigFile {
-    functions: HashMap<String,String>
+    functions: Functions,
+    /// Optional ABI signatures (e.g. `transfer(address,uint256)`) for
+    /// named functions.
+    #[serde(default)]
+    signatures: HashMap<String,String>
+}
+
+/// Identifies the functions given in a split file.  These are either
+/// given as a map from names to (hex) offsets within the first code
+/// section, or as a list of roots each with an explicit code section.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Functions {
+    Offsets(HashMap<String,String>),
+    Roots(Vec<PublicFunction>)
 }
 
 struct BlockGroup {
     id: usize,
     name: String,
     blocks: Vec<Block>,
-    deps: Vec<usize>
+    deps: Vec<usize>,
+    /// Signals the analysis of the enclosing code section was
+    /// incomplete.
+    incomplete: bool
 }
 
 type DomSet = SortedVec<usize>;
@@ -185,11 +996,11 @@ fn deconstruct<'a>(contract: &'a Assembly, settings: &'a Config) -> Vec<ControlF
     for (i,s) in contract.iter().enumerate() {
         match s {
             StructuredSection::Code(insns) => {
-                let mut cfg = ControlFlowGraph::new(i,blocksize,insns.as_ref(), settings.checks, settings.limit);
+                let mut cfg = ControlFlowGraph::new(i,blocksize,insns.as_ref(), &settings.checks, &settings.jump_targets, settings.limit, settings.merge_straightline, settings.inline_fallthrough);
                 cfgs.push(cfg);
             }
-            StructuredSection::Data(bytes) => {
-                // Nothing (for now)
+            StructuredSection::Data(_) => {
+                // Nothing to analyse (see write_headers)
             }
         }
     }
@@ -197,15 +1008,168 @@ fn deconstruct<'a>(contract: &'a Assembly, settings: &'a Config) -> Vec<ControlF
     cfgs
 }
 
+/// Describe the files which would be generated for a given set of
+/// groups, along with the number of blocks in each and the
+/// dependencies between groups.
+fn plan(files: &HashMap<String,String>, groups: &[BlockGroup]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut filenames : Vec<&String> = files.keys().collect();
+    filenames.sort();
+    for f in &filenames {
+        let n = files[*f].lines().map(|l| l.trim_start()).filter(|l| l.starts_with("method block_") || l.starts_with("function block_")).count();
+        if n > 0 { lines.push(format!("{f} ({n} block(s))")); } else { lines.push(f.to_string()); }
+    }
+    for g in groups {
+        let deps : Vec<&str> = g.deps.iter().map(|d| groups[*d].name.as_str()).collect();
+        if deps.len() > 0 {
+            lines.push(format!("\t{} -> {}",g.name,deps.join(", ")));
+        }
+    }
+    lines.push(format!("{} file(s) in {} group(s)",filenames.len(),groups.len()));
+    lines
+}
+
+/// Report the unreachable (i.e. deadcode) blocks in each code section,
+/// along with the total number found.
+fn report_deadcode(cfgs: &[ControlFlowGraph]) {
+    let dead = deadcode(cfgs);
+    for (cid,b) in &dead {
+        let reason = b.unreachable_reason().map_or(String::new(),|r| format!(" ({r})"));
+        eprintln!("DEADCODE: section {cid} block {:#06x}{reason}",b.pc());
+    }
+    eprintln!("DEADCODE: {} unreachable block(s)",dead.len());
+}
+
+/// Determine the unreachable blocks of every code section, along
+/// with the section containing each.
+fn deadcode<'a>(cfgs: &'a [ControlFlowGraph]) -> Vec<(usize,&'a Block)> {
+    cfgs.iter().flat_map(|cfg| {
+        cfg.blocks().iter().filter(|b| b.is_unreachable()).map(move |b| (cfg.cid(),b))
+    }).collect()
+}
+
+/// Identifies a strategy for splitting blocks into groups.
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum GroupBy {
+    /// Group blocks by the root (i.e. function) which owns them.
+    Function,
+    /// Group each loop (i.e. strongly connected component) together.
+    Scc,
+    /// Group all blocks together.
+    Single
+}
+
+impl std::str::FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self,Self::Err> {
+        match s {
+            "function" => Ok(GroupBy::Function),
+            "scc" => Ok(GroupBy::Scc),
+            "single" => Ok(GroupBy::Single),
+            _ => Err(format!("unknown grouping strategy \"{s}\""))
+        }
+    }
+}
+
 // Given a sequence of blocks, generate a set of block groups.
-fn group(roots: HashMap<(usize,usize),String>, cfgs: &[ControlFlowGraph]) -> Vec<BlockGroup> {
+fn group(roots: HashMap<(usize,usize),String>, cfgs: &[ControlFlowGraph], strategy: GroupBy) -> Vec<BlockGroup> {
     let mut groups = Vec::new();
     //
-    for cfg in cfgs { groups.extend(split(&roots,cfg)); }
+    for cfg in cfgs {
+        let mut gs = match strategy {
+            GroupBy::Function => split(&roots,cfg),
+            GroupBy::Scc => split_sccs(cfg),
+            GroupBy::Single => vec![new_group(cfg,&format!("main_{}",cfg.cid()),cfg.blocks().to_vec())]
+        };
+        // Determine dependencies (offset by the groups of earlier
+        // code sections)
+        let offset = groups.len();
+        for i in 0..gs.len() {
+            gs[i].deps = dependencies(i,&gs,cfg).into_iter().map(|j| j + offset).collect();
+        }
+        // Determine owning functions and loops
+        let headers = cfg.loop_headers();
+        let looping = cfg.loop_blocks();
+        for g in &mut gs {
+            for b in &mut g.blocks {
+                b.set_loop_header(headers.contains(&b.pc()));
+                b.set_in_loop(looping.binary_search(&b.pc()).is_ok());
+                let owned = cfg.roots().iter().any(|r| cfg.owns(*r,b));
+                let rs = if owned { cfg.dominating_roots(b.pc()) } else { cfg.sharing_roots(b.pc()) };
+                let names = rs.iter().filter_map(|r| roots.get(&(cfg.cid(),*r)).cloned()).collect();
+                b.set_owners(names,!owned);
+            }
+        }
+        groups.extend(gs);
+    }
+    // Determine dependencies on the entry blocks of other code
+    // sections (i.e. via `CALLF`)
+    for i in 0..groups.len() {
+        let cids : Vec<usize> = groups[i].blocks.iter().flat_map(|b| b.callf_targets()).collect();
+        for c in cids {
+            let j = groups.iter().position(|g| g.id == c && g.blocks.iter().any(|b| b.pc() == 0));
+            match j {
+                Some(j) if j != i && !groups[i].deps.contains(&j) => { groups[i].deps.push(j); }
+                _ => {}
+            }
+        }
+    }
+    //
+    groups
+}
+
+/// Construct a group with a given name and set of blocks from a given
+/// code section.
+fn new_group(cfg: &ControlFlowGraph, name: &str, blocks: Vec<Block>) -> BlockGroup {
+    BlockGroup{
+        id: cfg.cid(),
+        name: name.to_string(),
+        blocks,
+        deps: Vec::new(),
+        incomplete: cfg.is_incomplete()
+    }
+}
+
+/// Split a given sequence of blocks (in the same code segment) such
+/// that each non-trivial strongly connected component (i.e. loop) is
+/// in its own group.  Remaining blocks are placed together, except
+/// where separated by a loop.  Groups follow a topological order of
+/// the components, hence each group only depends on those after it
+/// (i.e. imports between groups are acyclic).
+fn split_sccs(cfg: &ControlFlowGraph) -> Vec<BlockGroup> {
+    let cid = cfg.cid();
+    let mut groups = Vec::new();
+    let mut rest = Vec::new();
+    // Components are produced callees first
+    for scc in cfg.sccs().into_iter().rev() {
+        let blocks : Vec<Block> = cfg.blocks().iter().filter(|b| scc.contains(&b.pc())).cloned().collect();
+        // A single block is only a loop if it reaches itself
+        if blocks.len() > 1 || blocks[0].successors().contains(&blocks[0].pc()) {
+            split_rest(cfg,&mut groups,&mut rest);
+            let name = format!("scc_{cid}_{}",groups.len());
+            groups.push(new_group(cfg,&name,blocks));
+        } else {
+            rest.extend(blocks);
+        }
+    }
+    split_rest(cfg,&mut groups,&mut rest);
     //
     groups
 }
 
+/// Move any remaining (i.e. non-loop) blocks into a new group.  The
+/// first such group of a code section is its "main" group.
+fn split_rest(cfg: &ControlFlowGraph, groups: &mut Vec<BlockGroup>, rest: &mut Vec<Block>) {
+    if rest.len() > 0 {
+        let cid = cfg.cid();
+        let name = if groups.is_empty() { format!("main_{cid}") } else { format!("main_{cid}_{}",groups.len()) };
+        let mut blocks = std::mem::take(rest);
+        blocks.sort_by_key(|b| b.pc());
+        groups.push(new_group(cfg,&name,blocks));
+    }
+}
+
 /// Split a given sequence of blocks (in the same code segment) upto
 /// into one or more groups.
 fn split(roots: &HashMap<(usize,usize),String>, cfg: &ControlFlowGraph) -> Vec<BlockGroup> {
@@ -214,24 +1178,15 @@ fn split(roots: &HashMap<(usize,usize),String>, cfg: &ControlFlowGraph) -> Vec<B
     // Split out groups
     for r in cfg.roots() {
         let blocks = cfg.get_owned(*r);
-        let name = roots.get(&(cid,*r)).unwrap().clone();
-        groups.push(BlockGroup{id: cid, name, blocks, deps: Vec::new()});
+        let name = roots.get(&(cid,*r)).unwrap();
+        groups.push(new_group(cfg,name,blocks));
     }
     // Add utility group (if applicable)
     let remainder = determine_remainder(&groups,&cfg);
     //
     if remainder.len() > 0 {
         // Yes, applicable
-        groups.push(BlockGroup{
-            id: cid,
-            name: "util".to_string(),
-            blocks: remainder,
-            deps: Vec::new()
-        });
-    }
-    // Determine dependencies
-    for i in 0..groups.len() {
-        groups[i].deps = dependencies(i,&groups, cfg);
+        groups.push(new_group(cfg,"util",remainder));
     }
     //
     groups
@@ -293,46 +1248,458 @@ fn touches_any(cfg: &ControlFlowGraph, from: &[Block], to: &[Block]) -> bool {
 
 /// Convert each block group into a sequence of one or more files
 /// using a given prefix.
-fn write_groups(groups: Vec<BlockGroup>, settings: &Config) -> Result<(), Box<dyn Error>> {
+fn write_groups(out: &Files, groups: &[BlockGroup], settings: &Config) -> Result<(), Box<dyn Error>> {
     let devmdir = &settings.devmdir;
     let prefix = &settings.prefix;
-    //
-    for i in 0..groups.len() {
+    // Split groups into parts (if applicable)
+    let parts : Vec<Vec<Vec<&Block>>> = groups.iter().map(|g| partition(g,settings)).collect();
+    let modules : Vec<Vec<String>> = groups.iter().zip(&parts).map(|(g,ps)| part_names(g,ps.len())).collect();
+    // Groups are written in parallel, since each is independent
+    let files : Vec<Vec<String>> = (0..groups.len()).into_par_iter().map(|i| {
         let g = &groups[i];
-        let filename = format!("{prefix}_{}_{}.dfy",g.id,g.name);
+        let mut files = Vec::new();
         let header = format!("{prefix}_{}_header.dfy",g.id);        
-        println!("Writing {filename}");
-        let mut f = BufWriter::new(File::create(filename)?);
-        writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"");
-        writeln!(f,"include \"{devmdir}/src/dafny/core/code.dfy\"");        
-        writeln!(f,"include \"{header}\"");
-        for d in &g.deps {
-            let dep = format!("{prefix}_{}_{}.dfy",g.id,&groups[*d].name);
-            writeln!(f,"include \"{dep}\"");            
-        }
-        writeln!(f,"");
-        writeln!(f,"module {} {{",g.name);
-        writeln!(f,"\timport opened Opcode");
-        writeln!(f,"\timport opened Code");
-        writeln!(f,"\timport opened Memory");
-        writeln!(f,"\timport opened Bytecode");
-        writeln!(f,"\timport opened Header");
-        for d in &g.deps {
-            writeln!(f,"\timport opened {}",&groups[*d].name);            
-        }        
-        // Write out imports for dependencies
-        writeln!(f,"");                
-        // Construct block printer
-        let mut printer = BlockPrinter::new(g.id,&mut f,settings);
-        //
-        for blk in &g.blocks { printer.print_block(&blk); }
-        writeln!(f,"}}");
+        for (k,blocks) in parts[i].iter().enumerate() {
+            let filename = format!("{prefix}_{}_{}.dfy",g.id,modules[i][k]);
+            out.write(&filename, |mut f| {
+                if g.incomplete { write_incomplete_banner(&mut f)?; }
+                writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"")?;
+                writeln!(f,"include \"{devmdir}/src/dafny/core/code.dfy\"")?;        
+                if !settings.include_guard {
+                    writeln!(f,"include \"{header}\"")?;
+                    for d in &g.deps {
+                        for m in &modules[*d] {
+                            writeln!(f,"include \"{prefix}_{}_{m}.dfy\"",groups[*d].id)?;
+                        }
+                    }
+                    // Earlier parts of this group
+                    for m in &modules[i][..k] {
+                        writeln!(f,"include \"{prefix}_{}_{m}.dfy\"",g.id)?;
+                    }
+                }
+                writeln!(f,"")?;
+                write_group_part(&mut f, g, &modules, i, k, blocks, settings)
+            })?;
+            files.push(filename);
+        }
+        Ok(files)
+    }).collect::<Result<_,String>>()?;
+    if settings.include_guard { write_aggregate(out,groups,&files.concat(),settings)?; }
+    Ok(())
+}
+
+/// Write out the module for a given part of a group, which imports the
+/// modules of all dependencies and of earlier parts.
+fn write_group_part<T:Write>(mut f: T, g: &BlockGroup, modules: &[Vec<String>], i: usize, k: usize, blocks: &[&Block], settings: &Config) -> std::io::Result<()> {
+    let t = &settings.indent;
+    writeln!(f,"module {} {{",modules[i][k])?;
+    writeln!(f,"{t}import opened Opcode")?;
+    writeln!(f,"{t}import opened Code")?;
+    writeln!(f,"{t}import opened Memory")?;
+    writeln!(f,"{t}import opened Bytecode")?;
+    writeln!(f,"{t}import opened {}",header_module(g.id,settings))?;
+    for d in &g.deps {
+        for m in &modules[*d] {
+            writeln!(f,"{t}import opened {m}")?;
+        }
+    }        
+    for m in &modules[i][..k] {
+        writeln!(f,"{t}import opened {m}")?;
+    }
+    // Write out imports for dependencies
+    writeln!(f,"")?;                
+    // Construct block printer
+    let mut printer = BlockPrinter::new(g.id,&mut f,settings);
+    //
+    for blk in blocks { printer.print_block(blk)?; }
+    if settings.reachability_lemma {
+        printer.print_reachability_lemma(&modules[i][k],blocks)?;
     }
+    writeln!(f,"}}")?;
     Ok(())
 }
+
+/// Write everything (i.e. headers and all group modules) into a single
+/// file.  Library includes are written once at the top, and modules
+/// are ordered such that dependencies precede their dependents.
+fn write_single(out: &Files, contract: &Assembly, groups: &[BlockGroup], settings: &Config) -> Result<(), Box<dyn Error>> {
+    let devmdir = &settings.devmdir;
+    let filename = format!("{}.dfy",settings.prefix);
+    let parts : Vec<Vec<Vec<&Block>>> = groups.iter().map(|g| partition(g,settings)).collect();
+    let modules : Vec<Vec<String>> = groups.iter().zip(&parts).map(|(g,ps)| part_names(g,ps.len())).collect();
+    // Determine module order
+    let mut order = Vec::new();
+    let mut visited = vec![false; groups.len()];
+    let mut acyclic = true;
+    for i in 0..groups.len() { acyclic &= topological_order(i,groups,&mut visited,&mut order); }
+    if !acyclic {
+        println!("WARNING: cyclic dependencies between groups (consider --group-by scc)");
+    }
+    //
+    out.write(&filename, |mut f| {
+        writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"")?;
+        writeln!(f,"include \"{devmdir}/src/dafny/state.dfy\"")?;
+        writeln!(f,"include \"{devmdir}/src/dafny/core/code.dfy\"")?;
+        writeln!(f,"")?;
+        for (i,s) in contract.iter().enumerate() {
+            if let StructuredSection::Code(insns) = s {
+                write_header(&mut f, contract, i, insns, settings)?;
+                writeln!(f,"")?;
+            }
+        }
+        for i in order {
+            let g = &groups[i];
+            if g.incomplete { write_incomplete_banner(&mut f)?; }
+            for (k,blocks) in parts[i].iter().enumerate() {
+                write_group_part(&mut f, g, &modules, i, k, blocks, settings)?;
+                writeln!(f,"")?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Append a given group to a topological ordering, after all of its
+/// dependencies.  This returns `false` if some dependency cycles back
+/// to a group not yet ordered, in which case no such ordering exists.
+fn topological_order(i: usize, groups: &[BlockGroup], visited: &mut Vec<bool>, order: &mut Vec<usize>) -> bool {
+    // Groups visited but not yet ordered are still being explored
+    if visited[i] { return order.contains(&i); }
+    visited[i] = true;
+    let mut acyclic = true;
+    for d in &groups[i].deps {
+        acyclic &= topological_order(*d,groups,visited,order);
+    }
+    order.push(i);
+    acyclic
+}
+
+/// Split the blocks of a group into one or more parts, such that each
+/// part is (where possible) under the configured output size and
+/// method count.  Blocks
+/// in the same strongly connected component are kept together, and
+/// parts are ordered such that each only references blocks in itself
+/// or earlier parts.  This ensures the imports between parts are
+/// acyclic.
+fn partition<'a>(g: &'a BlockGroup, settings: &Config) -> Vec<Vec<&'a Block>> {
+    if settings.split_output_size.is_none() && settings.max_methods_per_file.is_none() {
+        return vec![g.blocks.iter().collect()];
+    }
+    let cap = settings.split_output_size.unwrap_or(usize::MAX);
+    let max = settings.max_methods_per_file.unwrap_or(usize::MAX);
+    let offsets : HashMap<usize,usize> = g.blocks.iter().enumerate().map(|(i,b)| (b.pc(),i)).collect();
+    // Render each block once (if applicable), since this is expensive
+    let sizes : Vec<usize> = if cap == usize::MAX {
+        vec![0; g.blocks.len()]
+    } else {
+        g.blocks.par_iter().map(|b| block_size(g.id,b,settings)).collect()
+    };
+    let mut parts = Vec::new();
+    let mut part : Vec<&Block> = Vec::new();
+    let mut size = 0;
+    // Components are produced callees first
+    for scc in cfg::sccs(&g.blocks) {
+        let blocks : Vec<&Block> = scc.iter().map(|pc| &g.blocks[offsets[pc]]).collect();
+        let n = scc.iter().fold(0,|n,pc| n + sizes[offsets[pc]]);
+        if part.len() > 0 && (size + n > cap || part.len() + blocks.len() > max) {
+            parts.push(part);
+            part = Vec::new();
+            size = 0;
+        }
+        if n > cap {
+            println!("WARNING: loop at {:#06x} exceeds output size ({n} bytes)",scc[0]);
+        }
+        if blocks.len() > max {
+            println!("WARNING: loop at {:#06x} exceeds methods per file ({} blocks)",scc[0],blocks.len());
+        }
+        part.extend(blocks);
+        size += n;
+    }
+    if part.len() > 0 || parts.len() == 0 { parts.push(part); }
+    // Keep blocks in their original order within each part
+    for p in &mut parts { p.sort_by_key(|b| b.pc()); }
+    parts
+}
+
+/// Determine the module (and file) names used for the parts of a
+/// group.  A group which is not split retains its own name.
+/// Otherwise, since Dafny modules cannot be reopened, each part is
+/// a distinct module `{name}_{k}`.  Callers in other groups import
+/// every part of the group, hence their calls still resolve.
+fn part_names(g: &BlockGroup, n: usize) -> Vec<String> {
+    if n == 1 {
+        vec![g.name.clone()]
+    } else {
+        (0..n).map(|k| format!("{}_{k}",g.name)).collect()
+    }
+}
+
+/// Determine the size (in bytes) of the generated code for a block.
+fn block_size(id: usize, blk: &Block, settings: &Config) -> usize {
+    let mut buf = Vec::new();
+    // Writing into memory cannot fail
+    BlockPrinter::new(id,&mut buf,settings).print_block(blk).unwrap();
+    buf.len()
+}
  
+/// Write each block from every group into its own file.  Each file
+/// includes (and imports) only the files for blocks it can transfer
+/// control to.  Blocks in the same loop (i.e. strongly connected
+/// component) are written into the same file, since otherwise their
+/// files would include (and import) each other.
+fn write_blocks(out: &Files, groups: &[BlockGroup], settings: &Config) -> Result<(), Box<dyn Error>> {
+    let devmdir = &settings.devmdir;
+    let prefix = &settings.prefix;
+    let sections = section_blocks(groups);
+    let count = sections.iter().fold(0,|n,(_,_,blocks)| n + block_modules(blocks).len());
+    let mut files = Vec::new();
+    //
+    println!("WARNING: writing {count} files (one per block)");
+    //
+    for (id,incomplete,blocks) in &sections {
+        let header = format!("{prefix}_{id}_header.dfy");
+        let modules = block_modules(blocks);
+        let owners : HashMap<usize,usize> = modules.iter().flat_map(|(m,bs)| bs.iter().map(move |b| (b.pc(),*m))).collect();
+        for (pc,members) in &modules {
+            let filename = format!("{prefix}_{id}_{pc:#06x}.dfy");
+            // Determine modules for successors outside this one
+            let mut succs : Vec<usize> = members.iter().flat_map(|b| b.successors()).filter_map(|s| owners.get(&s).copied()).collect();
+            succs.sort_unstable();
+            succs.dedup();
+            succs.retain(|m| m != pc);
+            // Determine code sections called from this one
+            let mut cids : Vec<usize> = members.iter().flat_map(|b| b.callf_targets()).collect();
+            cids.sort_unstable();
+            cids.dedup();
+            cids.retain(|c| c != id);
+            out.write(&filename, |mut f| {
+                if *incomplete { write_incomplete_banner(&mut f)?; }
+                writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"")?;
+                writeln!(f,"include \"{devmdir}/src/dafny/core/code.dfy\"")?;
+                if !settings.include_guard {
+                    writeln!(f,"include \"{header}\"")?;
+                    for m in &succs {
+                        writeln!(f,"include \"{prefix}_{id}_{m:#06x}.dfy\"")?;
+                    }
+                    for c in &cids {
+                        writeln!(f,"include \"{prefix}_{c}_0x0000.dfy\"")?;
+                    }
+                }
+                writeln!(f,"")?;
+                writeln!(f,"module Block_{id}_{pc:#06x} {{")?;
+                let t = &settings.indent;
+                writeln!(f,"{t}import opened Opcode")?;
+                writeln!(f,"{t}import opened Code")?;
+                writeln!(f,"{t}import opened Memory")?;
+                writeln!(f,"{t}import opened Bytecode")?;
+                writeln!(f,"{t}import opened {}",header_module(*id,settings))?;
+                for m in &succs {
+                    writeln!(f,"{t}import opened Block_{id}_{m:#06x}")?;
+                }
+                for c in &cids {
+                    writeln!(f,"{t}import opened Block_{c}_0x0000")?;
+                }
+                writeln!(f,"")?;
+                let mut printer = BlockPrinter::new(*id,&mut f,settings);
+                for blk in members { printer.print_block(blk)?; }
+                writeln!(f,"}}")?;
+                Ok(())
+            })?;
+            files.push(filename);
+        }
+    }
+    if settings.include_guard { write_aggregate(out,groups,&files,settings)?; }
+    Ok(())
+}
+
+/// Collect the blocks of every group by code section, along with
+/// whether the analysis of that section was incomplete.  Blocks are
+/// given in order.
+fn section_blocks(groups: &[BlockGroup]) -> Vec<(usize,bool,Vec<Block>)> {
+    let mut sections : Vec<(usize,bool,Vec<Block>)> = Vec::new();
+    for g in groups {
+        match sections.iter_mut().find(|(id,_,_)| *id == g.id) {
+            Some((_,incomplete,blocks)) => {
+                *incomplete |= g.incomplete;
+                blocks.extend(g.blocks.iter().cloned());
+            }
+            None => { sections.push((g.id,g.incomplete,g.blocks.clone())); }
+        }
+    }
+    for (_,_,blocks) in &mut sections { blocks.sort_by_key(|b| b.pc()); }
+    sections
+}
+
+/// Determine the modules used when each block of a code section is
+/// written into its own file.  Each module is identified by the
+/// offset of its first block, and contains either a single block or
+/// an entire loop (i.e. strongly connected component).  Modules are
+/// given in order.
+fn block_modules(blocks: &[Block]) -> Vec<(usize,Vec<&Block>)> {
+    let mut modules : Vec<(usize,Vec<&Block>)> = cfg::sccs(blocks).iter().map(|scc| {
+        let members : Vec<&Block> = blocks.iter().filter(|b| scc.contains(&b.pc())).collect();
+        (scc[0],members)
+    }).collect();
+    modules.sort_by_key(|(pc,_)| *pc);
+    modules
+}
+
+/// Write a single top-level file which includes every header exactly
+/// once, followed by every generated file.  This is used when
+/// generated files don't include each other, to avoid duplicate
+/// module errors.
+fn write_aggregate(out: &Files, groups: &[BlockGroup], files: &[String], settings: &Config) -> Result<(), Box<dyn Error>> {
+    let prefix = &settings.prefix;
+    let filename = format!("{prefix}.dfy");
+    let mut ids : Vec<usize> = groups.iter().map(|g| g.id).collect();
+    ids.dedup();
+    out.write(&filename, |f| {
+        for id in ids {
+            writeln!(f,"include \"{prefix}_{id}_header.dfy\"")?;
+        }
+        for file in files {
+            writeln!(f,"include \"{file}\"")?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Verify each of a given set of files using Dafny, reporting whether
+/// each passed or failed (and how long it took).  Dafny's own output
+/// is passed through as it is produced.
+fn verify(files: &[String], settings: &Config) -> Result<(), Box<dyn Error>> {
+    let mut failed = 0;
+    for file in files {
+        println!("Verifying {file}");
+        let start = std::time::Instant::now();
+        let status = std::process::Command::new(&settings.dafny).arg("verify").arg(file).status()
+            .map_err(|e| format!("error running {}: {e}",settings.dafny))?;
+        let elapsed = start.elapsed().as_secs_f64();
+        if status.success() {
+            println!("PASS {file} ({elapsed:.1}s)");
+        } else {
+            println!("FAIL {file} ({elapsed:.1}s)");
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} of {} file(s) failed verification",files.len()).into());
+    }
+    Ok(())
+}
+
+/// Analysis results for a given code section, as written by `--json`.
+#[derive(Serialize,Deserialize)]
+struct SectionAnalysis {
+    section: usize,
+    blocks: Vec<BlockAnalysis>
+}
+
+/// Analysis results for a given block, as written by `--json`.
+#[derive(Serialize,Deserialize)]
+struct BlockAnalysis {
+    pc: usize,
+    stack_bounds: Option<(usize,usize)>,
+    freemem_ptr_bounds: Option<(usize,usize)>,
+    reachable: bool,
+    group: String
+}
+
+/// Write the results of the analysis (i.e. for every block in every
+/// code section) in a machine-readable form.  Sections and blocks are
+/// written in order, so the results are stable for diffing.
+fn write_analysis(out: &Files, filename: &str, groups: &[BlockGroup]) -> Result<(), Box<dyn Error>> {
+    let mut sections : Vec<SectionAnalysis> = Vec::new();
+    for g in groups {
+        let i = match sections.iter().position(|s| s.section == g.id) {
+            Some(i) => i,
+            None => {
+                sections.push(SectionAnalysis{section: g.id, blocks: Vec::new()});
+                sections.len() - 1
+            }
+        };
+        for blk in &g.blocks {
+            sections[i].blocks.push(BlockAnalysis{
+                pc: blk.pc(),
+                stack_bounds: blk.stack_bounds(),
+                freemem_ptr_bounds: blk.freemem_ptrs(),
+                reachable: !blk.is_unreachable(),
+                group: g.name.clone()
+            });
+        }
+    }
+    sections.sort_by_key(|s| s.section);
+    for s in &mut sections { s.blocks.sort_by_key(|b| b.pc); }
+    let json = serde_json::to_string_pretty(&sections)?;
+    out.write(filename, |f| f.write_all(json.as_bytes()))?;
+    Ok(())
+}
+
+/// Write a single machine-readable summary of everything generated,
+/// covering files, groups, blocks, the call graph between groups,
+/// warnings and overall statistics.  Objects are serialised with
+/// sorted keys, and arrays in a fixed order, so the summary is stable
+/// for diffing.
+fn write_summary(out: &Files, filename: &str, groups: &[BlockGroup], files: Vec<String>, settings: &Config) -> Result<(), Box<dyn Error>> {
+    let mut gsummary = Vec::new();
+    let mut bsummary = Vec::new();
+    let mut calls = Vec::new();
+    let mut warnings = Vec::new();
+    let mut unreachable = 0;
+    for g in groups {
+        let deps : Vec<&str> = g.deps.iter().map(|d| groups[*d].name.as_str()).collect();
+        for d in &deps {
+            calls.push(serde_json::json!({"section": g.id, "from": g.name, "to": d}));
+        }
+        if g.incomplete {
+            warnings.push(format!("incomplete analysis of code section {} (group {})",g.id,g.name));
+        }
+        gsummary.push(serde_json::json!({
+            "section": g.id,
+            "name": g.name,
+            "blocks": g.blocks.iter().map(|b| format!("{:#06x}",b.pc())).collect::<Vec<_>>(),
+            "deps": deps,
+            "incomplete": g.incomplete
+        }));
+        for blk in &g.blocks {
+            if blk.is_unreachable() { unreachable += 1; }
+            bsummary.push(serde_json::json!({
+                "section": g.id,
+                "group": g.name,
+                "pc": format!("{:#06x}",blk.pc()),
+                "unreachable": blk.is_unreachable(),
+                "stack_bounds": blk.stack_bounds(),
+                "stack_delta": blk.stack_delta(),
+                "successors": blk.successors().iter().map(|pc| format!("{pc:#06x}")).collect::<Vec<_>>()
+            }));
+        }
+    }
+    if settings.file_per_block {
+        let n = files.iter().filter(|f| !f.ends_with("_header.dfy")).count();
+        warnings.push(format!("writing {n} files (one per block)"));
+    }
+    let summary = serde_json::json!({
+        "files": files,
+        "groups": gsummary,
+        "blocks": bsummary,
+        "callgraph": calls,
+        "warnings": warnings,
+        "statistics": {
+            "files": files.len(),
+            "groups": groups.len(),
+            "blocks": bsummary.len(),
+            "unreachable": unreachable
+        }
+    });
+    let json = serde_json::to_string_pretty(&summary)?;
+    out.write(filename, |f| f.write_all(json.as_bytes()))?;
+    Ok(())
+}
+
 /// Write out header files for all bytecode sections.
-fn write_headers(contract: &Assembly, settings: &Config) -> Result<(), Box<dyn Error>> {
+fn write_headers(out: &Files, contract: &Assembly, settings: &Config) -> Result<(), Box<dyn Error>> {
     let devmdir = &settings.devmdir;    
     let prefix = &settings.prefix;
     //
@@ -340,94 +1707,216 @@ fn write_headers(contract: &Assembly, settings: &Config) -> Result<(), Box<dyn E
         match s {
             StructuredSection::Code(insns) => {
                 let filename = format!("{prefix}_{}_header.dfy",i);
-                println!("Writing {filename}");
-                let mut f = BufWriter::new(File::create(filename)?);
-                writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"")?;
-                writeln!(f,"include \"{devmdir}/src/dafny/state.dfy\"")?;               
-                writeln!(f,"")?;
-                writeln!(f,"module Header {{")?;
-                writeln!(f,"\timport opened Int");
-                writeln!(f,"\timport EvmState");
-                writeln!(f,"");                                
-                writeln!(f,"\ttype u256 = Int.u256");
-                writeln!(f,"\tconst MAX_U256 : nat := Int.MAX_U256");
-                writeln!(f,"");                
-                write_bytecode(&mut f, insns, i);
-                // for now
-                write_external_call(&mut f);
-		// Write custom masking implementations
-		if settings.masks {
-		    write_and_mask(&mut f, 1);
-		    write_and_mask(&mut f, 5);
-		    write_and_mask(&mut f, 8);
-		    write_and_mask(&mut f, 32);
-		    write_and_mask(&mut f, 64);
-		    write_and_mask(&mut f, 128);
-		    write_and_mask(&mut f, 160);
-		}
-                writeln!(f,"}}")?;
+                out.write(&filename, |mut f| {
+                    writeln!(f,"include \"{devmdir}/src/dafny/evm.dfy\"")?;
+                    writeln!(f,"include \"{devmdir}/src/dafny/state.dfy\"")?;               
+                    writeln!(f,"")?;
+                    write_header(&mut f, contract, i, insns, settings)
+                })?;
             }
-            StructuredSection::Data(bytes) => {
-                // Nothing (for now)
+            StructuredSection::Data(_) => {
+                // Written within the header of each code section
             }
         }
     }
     Ok(())
 }
 
-/// Write out the contract bytecode as an array of bytes.
-fn write_bytecode<T:Write>(mut f: T, insns: &[Instruction], id: usize) {
+/// Determine the name of the header module for a given code section.
+/// When everything is written into a single file, headers are named
+/// after their section since otherwise they would clash.  Likewise,
+/// headers of later sections are named after their section, since
+/// they may be included alongside the first (e.g. via `CALLF`).
+fn header_module(cid: usize, settings: &Config) -> String {
+    if settings.single_file || cid != 0 { format!("Header_{cid}") } else { "Header".to_string() }
+}
+
+/// Write out the header module for a given code section.
+fn write_header<T:Write>(mut f: T, contract: &Assembly, i: usize, insns: &[Instruction], settings: &Config) -> std::io::Result<()> {
+    let t = &settings.indent;
+    writeln!(f,"module {} {{",header_module(i,settings))?;
+    writeln!(f,"{t}import opened Int")?;
+    writeln!(f,"{t}import EvmState")?;
+    writeln!(f,"")?;                                
+    writeln!(f,"{t}type u256 = Int.u256")?;
+    writeln!(f,"{t}const MAX_U256 : nat := Int.MAX_U256")?;
+    writeln!(f,"{t}const MAX_U160 : nat := Int.MAX_U160")?;
+    writeln!(f,"")?;                
+    write_bytecode(&mut f, insns, i, settings.opaque_bytecode, t)?;
+    // Data sections are identified by their section index
+    for (j,s) in contract.iter().enumerate() {
+        if let StructuredSection::Data(bytes) = s {
+            write_bytes(&mut f, &format!("DATA_{j}"), bytes, false, t)?;
+        }
+    }
+    // for now
+    if !settings.no_external_call {
+        write_external_call(&mut f, settings.functional, settings.external_call.as_deref(), t)?;
+    }
+    // Write custom masking implementations
+    if settings.masks {
+        write_and_mask(&mut f, 1)?;
+        write_and_mask(&mut f, 5)?;
+        write_and_mask(&mut f, 8)?;
+        write_and_mask(&mut f, 32)?;
+        write_and_mask(&mut f, 64)?;
+        write_and_mask(&mut f, 128)?;
+        write_and_mask(&mut f, 160)?;
+    }
+    writeln!(f,"}}")?;
+    Ok(())
+}
+
+/// Maximum number of bytes in each chunk of a bytecode constant.  Very
+/// long sequence literals can overflow the stack in Dafny, so these
+/// are built up by concatenating chunks.
+const CHUNK_SIZE : usize = 240;
+
+/// Write out the contract bytecode as an array of bytes, using a given
+/// string for each level of indentation.
+fn write_bytecode<T:Write>(f: T, insns: &[Instruction], id: usize, opaque: bool, t: &str) -> std::io::Result<()> {
     // Convert instructions into bytes
-    let mut bytes = insns.assemble();   
+    let bytes = insns.assemble();   
+    write_bytes(f, &format!("BYTECODE_{id}"), &bytes, opaque, t)
+}
 
-    let chunksize = 160;
-    write!(f,"\tconst BYTECODE_{id}_0 : seq<u8> := [");
+/// Write out a sequence of bytes as a (chunked) constant with the
+/// given name, using a given string for each level of indentation.
+fn write_bytes<T:Write>(mut f: T, name: &str, bytes: &[u8], opaque: bool, t: &str) -> std::io::Result<()> {
+    let chunksize = CHUNK_SIZE;
+    write!(f,"{t}const {name}_0 : seq<u8> := [")?;
     for i in 0..bytes.len() {
         if i%8 == 0 {
-            write!(f,"\n\t\t");
+            write!(f,"\n{t}{t}")?;
         }
 
         if i>0 && i%chunksize == 0 {
             let chunknumber = i/chunksize;
             let chunknumber_prev = chunknumber - 1;
-            write!(f,"]\n\tconst BYTECODE_{id}_{chunknumber} : seq<u8> := BYTECODE_{id}_{chunknumber_prev} + [\n\t\t");
+            write!(f,"]\n{t}const {name}_{chunknumber} : seq<u8> := {name}_{chunknumber_prev} + [\n{t}{t}")?;
         }
-        write!(f,"{:#02x}", bytes[i]);
+        write!(f,"{:#02x}", bytes[i])?;
         if ((i + 1) != bytes.len()) && ((i + 1)%chunksize != 0) {
-            write!(f,", ");
+            write!(f,", ")?;
         }
     }
 
-    writeln!(f,"\n\t]");
-    let chunknumber = bytes.len()/chunksize;
-    write!(f,"\tconst BYTECODE_{id} : seq<u8> := BYTECODE_{id}_{chunknumber}\n");
+    writeln!(f,"\n{t}]")?;
+    // Index of the final (possibly partial) chunk
+    let chunknumber = bytes.len().saturating_sub(1)/chunksize;
+    let attr = if opaque { "{:opaque} " } else { "" };
+    write!(f,"{t}const {attr}{name} : seq<u8> := {name}_{chunknumber}\n")?;
+    Ok(())
+}
+
+/// A collection of generated files, mapping each filename to its
+/// contents.  Files can be written concurrently (e.g. when groups are
+/// written in parallel).
+#[derive(Default)]
+struct Files(Mutex<HashMap<String,String>>);
+
+impl Files {
+    /// Write the contents of a given file using a given function.
+    /// Any failure is reported against the name of the file.
+    fn write<F>(&self, filename: &str, contents: F) -> Result<(), String>
+    where F: FnOnce(&mut Vec<u8>) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        contents(&mut buf).map_err(|e| format!("error writing {filename}: {e}"))?;
+        let text = String::from_utf8(buf).map_err(|e| format!("error writing {filename}: {e}"))?;
+        self.0.lock().unwrap().insert(filename.to_string(),text);
+        Ok(())
+    }
 
+    /// Get the names of all files written so far, in order.
+    fn filenames(&self) -> Vec<String> {
+        let mut names : Vec<String> = self.0.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Extract the mapping from filenames to their contents.
+    fn into_inner(self) -> HashMap<String,String> {
+        self.0.into_inner().unwrap()
+    }
 }
 
-fn write_external_call<T:Write>(mut f: T) {
-    writeln!(f,"\tmethod external_call(sender: u160, st: EvmState.ExecutingState) returns (r:EvmState.TerminatedState)");
-    writeln!(f,"\tensures r.RETURNS? ==> r.world.Exists(sender) {{");
-    writeln!(f,"\t\treturn EvmState.ERROR(EvmState.INSUFFICIENT_GAS); // dummy");
-    writeln!(f,"\t}}");
+/// Write a banner warning that the file was generated from an
+/// incomplete analysis.
+fn write_incomplete_banner<T:Write>(mut f: T) -> std::io::Result<()> {
+    writeln!(f,"// ======================================================================")?;
+    writeln!(f,"// WARNING: incomplete analysis")?;
+    writeln!(f,"//")?;
+    writeln!(f,"// Control-flow graph construction for this code section was incomplete.")?;
+    writeln!(f,"// Hence, the generated preconditions may be unsound or weak.")?;
+    writeln!(f,"// ======================================================================")?;
+    Ok(())
+}
+
+/// Write out the model of an external call, where `body` (if given)
+/// replaces the dummy implementation verbatim.
+fn write_external_call<T:Write>(mut f: T, functional: bool, body: Option<&str>, t: &str) -> std::io::Result<()> {
+    if functional {
+        writeln!(f,"{t}function external_call(sender: u160, st: EvmState.ExecutingState): (r:EvmState.TerminatedState)")?;
+    } else {
+        writeln!(f,"{t}method external_call(sender: u160, st: EvmState.ExecutingState) returns (r:EvmState.TerminatedState)")?;
+    }
+    writeln!(f,"{t}ensures r.RETURNS? ==> r.world.Exists(sender) {{")?;
+    match body {
+        Some(body) => { writeln!(f,"{}",body.trim_end())?; }
+        None if functional => { writeln!(f,"{t}{t}EvmState.ERROR(EvmState.INSUFFICIENT_GAS) // dummy")?; }
+        None => { writeln!(f,"{t}{t}return EvmState.ERROR(EvmState.INSUFFICIENT_GAS); // dummy")?; }
+    }
+    writeln!(f,"{t}}}")?;
+    Ok(())
 }
 
-fn write_and_mask<T:Write>(mut f: T, width: usize) {    
-    writeln!(f,"/**");
-    writeln!(f," * Alternative to Bytecode.And for masking u256 into a u{width}");
-    writeln!(f," */");
-    writeln!(f,"function AndU{width}(st: EvmState.ExecutingState): (st': EvmState.State)");
-    writeln!(f,"requires st.Operands() >= 2 && st.Peek(0) == (Int.MAX_U{width} as u256) {{");
-    writeln!(f,"    var rhs := st.Peek(1);");
-    writeln!(f,"    var res := rhs % (Int.TWO_{width} as u256);");
-    writeln!(f,"    st.Pop(2).Push(res).Next()");
-    writeln!(f,"}}");    
+fn write_and_mask<T:Write>(mut f: T, width: usize) -> std::io::Result<()> {
+    writeln!(f,"/**")?;
+    writeln!(f," * Alternative to Bytecode.And for masking u256 into a u{width}")?;
+    writeln!(f," */")?;
+    writeln!(f,"function AndU{width}(st: EvmState.ExecutingState): (st': EvmState.State)")?;
+    writeln!(f,"requires st.Operands() >= 2 && st.Peek(0) == (Int.MAX_U{width} as u256) {{")?;
+    writeln!(f,"    var rhs := st.Peek(1);")?;
+    writeln!(f,"    var res := rhs % (Int.TWO_{width} as u256);")?;
+    writeln!(f,"    st.Pop(2).Push(res).Next()")?;
+    writeln!(f,"}}")?;
+    Ok(())
 }
 
 // ===================================================================
 // Helpers
 // ===================================================================
 
-fn infer_havoc_insns(mut asm: Assembly, limit: usize) -> Assembly {
+/// Detect the public functions of a contract from the standard
+/// Solidity dispatcher within each of its code sections.  That is,
+/// sequences of the form `PUSH selector; EQ; PUSH dest; JUMPI` where
+/// both selector and destination are known constants.  Selectors with
+/// leading zero bytes are pushed using fewer than four bytes.  Each
+/// function is named after its selector.
+fn detect_roots(cfgs: &[ControlFlowGraph]) -> HashMap<(usize,usize),String> {
+    let mut roots = HashMap::new();
+    for cfg in cfgs {
+        for blk in cfg.blocks() {
+            // Ignore bytecodes which don't correspond to instructions
+            let bcs : Vec<(usize,&Bytecode)> = blk.iter().enumerate().filter(|(_,bc)| bc.length() > 0).collect();
+            for w in bcs.windows(4) {
+                match (w[0].1,w[1].1,w[2].1,w[3].1) {
+                    (Bytecode::Unit(PUSH(bytes)),Bytecode::Unit(EQ),Bytecode::Unit(PUSH(_)),Bytecode::JumpI(_)) if bytes.len() <= 4 => {
+                        let selector = blk.constant_operand(w[1].0,0);
+                        let dest = blk.constant_operand(w[3].0,0);
+                        if let (Some(s),Some(d)) = (selector,dest) {
+                            roots.insert((cfg.cid(),d),format!("fn_{s:#010x}"));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    roots
+}
+
+fn infer_havoc_insns(mut asm: Assembly, limit: usize, canonical: bool) -> Assembly {
     // This could probably be more efficient :)
     let sections = asm.iter_mut().map(|section| {
         match section {
@@ -439,6 +1928,7 @@ fn infer_havoc_insns(mut asm: Assembly, limit: usize) -> Assembly {
 			ninsns
 		    }
 		};
+                let ninsns = if canonical { canonicalise_havocs(ninsns) } else { ninsns };
 	        StructuredSection::Code(ninsns)
             }
             _ => section.clone()
@@ -448,13 +1938,47 @@ fn infer_havoc_insns(mut asm: Assembly, limit: usize) -> Assembly {
     Assembly::new(sections)
 }
 
+/// Canonicalise havoc instructions by sorting each run of adjacent
+/// havocs by stack position, and merging duplicates.  This ensures
+/// the placement of havocs does not depend on the order in which they
+/// were inserted.
+fn canonicalise_havocs(insns: Vec<Instruction>) -> Vec<Instruction> {
+    let mut ninsns = Vec::new();
+    let mut run = Vec::new();
+    //
+    for insn in insns {
+        if matches!(insn,HAVOC(_)) {
+            run.push(insn);
+        } else {
+            flush_havocs(&mut run, &mut ninsns);
+            ninsns.push(insn);
+        }
+    }
+    flush_havocs(&mut run, &mut ninsns);
+    //
+    ninsns
+}
+
+fn flush_havocs(run: &mut Vec<Instruction>, insns: &mut Vec<Instruction>) {
+    run.sort_by_key(havoc_index);
+    run.dedup_by_key(|insn| havoc_index(insn));
+    insns.append(run);
+}
+
+fn havoc_index(insn: &Instruction) -> usize {
+    match insn {
+        HAVOC(n) => *n as usize,
+        _ => unreachable!()
+    }
+}
+
 /// Add assertions to check against overflow / underflow in generated
 /// bytecode.
 fn overflow_checks(insn: &Instruction, codes: &mut Vec<Bytecode>) {
     let s = match insn {
-        ADD => "(st.Peek(0) + st.Peek(1)) <= (MAX_U256 as u256)",
-        MUL => "(st.Peek(0) * st.Peek(1)) <= (MAX_U256 as u256)",
-        SUB => "st.Peek(1) <= st.Peek(0)",
+        ADD => "($0 + $1) <= (MAX_U256 as u256)",
+        MUL => "($0 * $1) <= (MAX_U256 as u256)",
+        SUB => "$1 <= $0",
         _ => {
             // Do nothing in other cases
             return;
@@ -462,3 +1986,891 @@ fn overflow_checks(insn: &Instruction, codes: &mut Vec<Bytecode>) {
     };
     codes.push(Bytecode::Assert(vec![0,1],s.to_string()));
 }
+
+/// Add assertions which check that shift amounts are less than
+/// `256`.  A shift by `256` or more always produces `0`, which is
+/// often a bug in the source.
+fn shift_checks(insn: &Instruction, codes: &mut Vec<Bytecode>) {
+    match insn {
+        SHL|SHR|SAR => {
+            codes.push(Bytecode::Assert(vec![0],"$0 < 256".to_string()));
+        }
+        _ => {}
+    }
+}
+
+/// Add assertions which check that divisors are non-zero.  Division
+/// by zero always produces `0`, which is often a bug in the source.
+fn division_checks(insn: &Instruction, codes: &mut Vec<Bytecode>) {
+    match insn {
+        DIV|SDIV|MOD|SMOD => {
+            codes.push(Bytecode::Assert(vec![1],"$1 != 0".to_string()));
+        }
+        _ => {}
+    }
+}
+
+// ===================================================================
+// Tests
+// ===================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Determine the settings for a given set of command-line
+    /// arguments, for a target named `test.hex`.
+    fn config(args: &[&str]) -> Config {
+        let mut argv = vec!["devmpg"];
+        argv.extend(args);
+        argv.push("test.hex");
+        configure(&cli().get_matches_from(argv)).unwrap()
+    }
+
+    /// Generate files for a contract given as a hex string, using a
+    /// given set of command-line arguments.
+    fn generate_hex(hex: &str, args: &[&str]) -> HashMap<String,String> {
+        let settings = config(args);
+        let (_,contract) = load(hex.as_bytes().to_vec(),&settings).unwrap();
+        generate(&contract,&settings).unwrap()
+    }
+
+    /// Generate files for a contract given as a single code section,
+    /// using a given set of command-line arguments.
+    fn generate_insns(insns: Vec<Instruction>, args: &[&str]) -> HashMap<String,String> {
+        let settings = config(args);
+        let contract = Assembly::new(vec![StructuredSection::Code(insns)]);
+        generate(&contract,&settings).unwrap()
+    }
+
+    /// Check the includes between generated files are acyclic, since
+    /// otherwise Dafny rejects them.
+    fn assert_acyclic(files: &HashMap<String,String>) {
+        // Determine includes of each file (ignoring library files)
+        let includes : HashMap<&str,Vec<&str>> = files.iter().map(|(f,c)| {
+            let is = c.lines().filter_map(|l| l.strip_prefix("include \"")).map(|l| l.trim_end_matches('"')).filter(|i| files.contains_key(*i)).collect();
+            (f.as_str(),is)
+        }).collect();
+        // Repeatedly remove files including nothing which remains
+        let mut remaining : Vec<&str> = includes.keys().copied().collect();
+        while let Some(i) = remaining.iter().position(|f| includes[f].iter().all(|g| !remaining.contains(g))) {
+            remaining.remove(i);
+        }
+        assert!(remaining.is_empty(),"cyclic includes between {remaining:?}");
+    }
+
+    #[test]
+    fn test_generate_01() {
+        // PUSH1 0x1; PUSH1 0x2; ADD; STOP
+        let files = generate_hex("600160020100",&[]);
+        let mut names : Vec<&String> = files.keys().collect();
+        names.sort();
+        assert_eq!(names,["test_0_header.dfy","test_0_main.dfy"]);
+        assert!(files["test_0_header.dfy"].contains("module Header {"));
+        assert!(files["test_0_main.dfy"].contains("method block_0_0x0000("));
+        assert!(files["test_0_main.dfy"].contains("st := Add(st);"));
+    }
+
+    #[test]
+    fn test_generate_02() {
+        // Prefix defaults when none is configured
+        let mut settings = config(&[]);
+        settings.prefix = String::new();
+        let (_,contract) = load(b"600160020100".to_vec(),&settings).unwrap();
+        let files = generate(&contract,&settings).unwrap();
+        assert!(files.contains_key("main_0_main.dfy"));
+    }
+
+    #[test]
+    fn test_generate_03() {
+        // Analysis results and summary are generated alongside
+        let files = generate_hex("600160020100",&["--json","analysis.json","--summary-json","summary.json"]);
+        assert!(files["analysis.json"].contains("\"stack_bounds\""));
+        assert!(files["summary.json"].contains("\"test_0_main.dfy\""));
+    }
+
+    #[test]
+    fn test_file_per_block_01() {
+        // Loop between 0x02 and 0x0b, exiting to 0x13
+        let files = generate_hex("600a5b8015601357600b565b600190036002565b00",&["--file-per-block"]);
+        assert!(files.contains_key("test_0_0x0000.dfy"));
+        assert!(files.contains_key("test_0_0x0002.dfy"));
+        assert!(files.contains_key("test_0_0x0013.dfy"));
+        // Loop is written into a single module
+        assert!(!files.contains_key("test_0_0x000b.dfy"));
+        assert!(files["test_0_0x0002.dfy"].contains("method block_0_0x000b("));
+        assert!(files["test_0_0x0000.dfy"].contains("import opened Block_0_0x0002"));
+        assert_acyclic(&files);
+    }
+
+    #[test]
+    fn test_memory_assert_01() {
+        // PUSH1 0x80; PUSH1 0x40; MSTORE; PUSH1 0x2a; PUSH1 0x0; MSTORE;
+        // PUSH1 0x0; MLOAD; PUSH1 0x60; MLOAD; STOP
+        let files = generate_hex("6080604052602a60005260005160605100",&["--debug"]);
+        let main = &files["test_0_main.dfy"];
+        // Word previously written
+        assert!(main.contains("assert st.MemSize() >= 0x20;"));
+        // Zero slot lies below the free memory pointer, but memory
+        // need not extend that far.
+        assert!(!main.contains("assert st.MemSize() >= 0x80;"));
+        // Writes expand memory, so need no assertion
+        assert_eq!(main.matches("assert st.MemSize()").count(),1);
+    }
+
+    #[test]
+    fn test_group_by_01() {
+        // Loop between 0x02 and 0x0b, exiting to 0x13
+        let files = generate_hex("600a5b8015601357600b565b600190036002565b00",&["--group-by","scc"]);
+        assert!(files.contains_key("test_0_main_0.dfy"));
+        assert!(files.contains_key("test_0_scc_0_1.dfy"));
+        assert!(files.contains_key("test_0_main_0_2.dfy"));
+        // Blocks after the loop are imported by it
+        assert!(files["test_0_scc_0_1.dfy"].contains("import opened main_0_2"));
+        assert!(!files["test_0_scc_0_1.dfy"].contains("import opened main_0\n"));
+        assert_acyclic(&files);
+    }
+
+    #[test]
+    fn test_group_by_02() {
+        // Group named after its code section
+        let files = generate_hex("600160020100",&["--group-by","single"]);
+        assert!(files["test_0_main_0.dfy"].contains("module main_0 {"));
+    }
+
+    #[test]
+    fn test_precompile_01() {
+        // PUSH1 0x20; PUSH1 0x0; PUSH1 0x20; PUSH1 0x0; PUSH1 0x2; GAS; STATICCALL; STOP
+        let mut settings = config(&[]);
+        let model = PrecompileModel{name: "sha256".to_string(), returndatasize: Some(32), facts: vec!["st.Peek(0) != 0".to_string()]};
+        settings.precompiles.insert(0x2,model);
+        let (_,contract) = load(b"602060006020600060025afa00".to_vec(),&settings).unwrap();
+        let files = generate(&contract,&settings).unwrap();
+        let main = &files["test_0_main.dfy"];
+        // Facts only hold when the call succeeds
+        assert!(main.contains("assume {:axiom} st.EXECUTING? && st.Peek(0) == 1 ==> |st.evm.context.returnData| == 32;"));
+        assert!(main.contains("assume {:axiom} st.EXECUTING? && st.Peek(0) == 1 ==> st.Peek(0) != 0;"));
+    }
+
+    #[test]
+    fn test_stack_delta_01() {
+        // PUSH1 0x0; CALLDATALOAD; PUSH1 0x1; SWAP1; JUMP
+        let files = generate_hex("60003560019056",&[]);
+        assert!(files["test_0_main.dfy"].contains("ensures st''.EXECUTING? ==> st''.Operands() == st'.Operands() + 1"));
+    }
+
+    #[test]
+    fn test_stack_delta_02() {
+        // Vacuous for halting blocks
+        let files = generate_hex("600160020100",&[]);
+        assert!(!files["test_0_main.dfy"].contains("st''.Operands() == st'.Operands()"));
+    }
+
+    #[test]
+    fn test_precompile_02() {
+        // Facts refer to the current state variable
+        let mut settings = config(&["--state-var","s","--functional"]);
+        let model = PrecompileModel{name: "sha256".to_string(), returndatasize: None, facts: vec!["st.Peek(0) != 0".to_string()]};
+        settings.precompiles.insert(0x2,model);
+        let (_,contract) = load(b"602060006020600060025afa00".to_vec(),&settings).unwrap();
+        let files = generate(&contract,&settings).unwrap();
+        assert!(files["test_0_main.dfy"].contains("assume {:axiom} s7.EXECUTING? && s7.Peek(0) == 1 ==> s7.Peek(0) != 0;"));
+    }
+
+    #[test]
+    fn test_data_01() {
+        // Trailing data within a code section
+        let files = generate_insns(vec![PUSH(vec![0x1]),DATA(vec![0xde,0xad,0xbe,0xef])],&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("// Data (4 bytes): "));
+        assert!(main.contains("deadbeef"));
+    }
+
+    #[test]
+    fn test_never_taken_01() {
+        // PUSH1 0x0; PUSH1 0x6; JUMPI; STOP; JUMPDEST; STOP
+        let files = generate_insns(vec![PUSH(vec![0x0]),PUSH(vec![0x6]),JUMPI,STOP,JUMPDEST,STOP],&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("// Branch never taken"));
+        assert!(!main.contains("st := block_0_0x0006(st);"));
+    }
+
+    #[test]
+    fn test_diff_01() {
+        // PUSH1 0x1; PUSH1 0x2 (or 0x3); ADD; STOP
+        let old = generate_hex("600160020100",&["--functional"]);
+        let new = generate_hex("600160030100",&["--functional"]);
+        let report = diff_methods(&extract_methods(&old,"test"),&extract_methods(&new,"test"));
+        assert_eq!(report.len(),1);
+        assert_eq!(report["block_0_0x0000"]["signature"],serde_json::json!(false));
+        assert_eq!(report["block_0_0x0000"]["body"],serde_json::json!(true));
+    }
+
+    #[test]
+    fn test_rjump_01() {
+        // RJUMP 0x1; STOP; STOP
+        let files = generate_insns(vec![RJUMP(1),STOP,STOP],&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("st := RJump(st,1);"));
+        assert!(main.contains("st := block_0_0x0004(st);"));
+    }
+
+    #[test]
+    fn test_write_bytes_01() {
+        let mut buf = Vec::new();
+        write_bytes(&mut buf,"BYTECODE_0",&[0u8; 1000],false,"\t").unwrap();
+        let out = String::from_utf8(buf).unwrap();
+        // 1000 bytes requires five chunks
+        assert!(out.contains("const BYTECODE_0_0 : seq<u8> := ["));
+        for k in 1..5 {
+            assert!(out.contains(&format!("const BYTECODE_0_{k} : seq<u8> := BYTECODE_0_{} + [",k-1)));
+        }
+        assert!(!out.contains("BYTECODE_0_5"));
+        assert!(out.contains("const BYTECODE_0 : seq<u8> := BYTECODE_0_4\n"));
+    }
+
+    #[test]
+    fn test_functional_01() {
+        let files = generate_hex("600160020100",&["--functional"]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("function block_0_0x0000("));
+        assert!(main.contains("var st1 := Push1(st0,0x01);"));
+        assert!(!main.contains("st :="));
+    }
+
+    #[test]
+    fn test_storage_requires_01() {
+        // PUSH1 0x2a; PUSH1 0x1; SSTORE; PUSH1 0x8; JUMP; JUMPDEST; PUSH1 0x1; SLOAD; STOP
+        let files = generate_hex("602a6001556008565b60015400",&[]);
+        assert!(files["test_0_main.dfy"].contains("requires st'.Load(0x01) == 0x2a"));
+    }
+
+    #[test]
+    fn test_data_section_01() {
+        let settings = config(&[]);
+        let contract = Assembly::new(vec![StructuredSection::Code(vec![STOP]),StructuredSection::Data(vec![0x1,0x2,0x3])]);
+        let files = generate(&contract,&settings).unwrap();
+        let header = &files["test_0_header.dfy"];
+        assert!(header.contains("const BYTECODE_0 : seq<u8>"));
+        assert!(header.contains("const DATA_1 : seq<u8> := DATA_1_0"));
+        assert!(!files.contains_key("test_1_header.dfy"));
+    }
+
+    #[test]
+    fn test_div_checks_01() {
+        // PUSH1 0x2; PUSH1 0x4; DIV; STOP
+        let files = generate_hex("600260040400",&["--div-checks"]);
+        assert!(files["test_0_main.dfy"].contains("assert st.Peek(1) != 0;"));
+    }
+
+    #[test]
+    fn test_shift_checks_01() {
+        // PUSH1 0x1; PUSH2 0x100; SHL; STOP
+        let files = generate_hex("60016101001b00",&["--shift-checks"]);
+        let main = &files["test_0_main.dfy"];
+        let check = main.find("assert st.Peek(0) < 256;").unwrap();
+        assert!(check < main.find("st := Shl(st);").unwrap());
+    }
+
+    #[test]
+    fn test_checks_01() {
+        // PUSH1 0x2; PUSH1 0x4; ADD; PUSH1 0x2; DIV; STOP
+        let files = generate_hex("600260040160020400",&["--overflows","--div-checks"]);
+        let main = &files["test_0_main.dfy"];
+        let add = main.find("assert (st.Peek(0) + st.Peek(1)) <= (MAX_U256 as u256);").unwrap();
+        let div = main.find("assert st.Peek(1) != 0;").unwrap();
+        assert!(add < main.find("st := Add(st);").unwrap());
+        assert!(div < main.find("st := Div(st);").unwrap());
+    }
+
+    #[test]
+    fn test_checks_02() {
+        // No checks unless requested
+        let files = generate_hex("600260040160020400",&[]);
+        assert!(!files["test_0_main.dfy"].contains("assert "));
+    }
+
+    #[test]
+    fn test_detect_roots_01() {
+        // Dispatcher over selectors 0x12345678 and 0x00abcdef (pushed
+        // with PUSH3), jumping to 0x1a and 0x1c respectively.
+        let settings = config(&["--detect-roots"]);
+        let hex = "60003560e01c80631234567814601a578062abcdef14601c57005b005b00";
+        let (_,contract) = load(hex.as_bytes().to_vec(),&settings).unwrap();
+        let cfgs = deconstruct(&contract,&settings);
+        let roots = detect_roots(&cfgs);
+        assert_eq!(roots.len(),2);
+        assert_eq!(roots[&(0,0x1a)],"fn_0x12345678");
+        assert_eq!(roots[&(0,0x1c)],"fn_0x00abcdef");
+    }
+
+    #[test]
+    fn test_outcome_ensures_01() {
+        // PUSH1 0x0; PUSH1 0x0; RETURN
+        let files = generate_hex("60006000f3",&[]);
+        assert!(files["test_0_main.dfy"].contains("ensures st''.RETURNS?"));
+    }
+
+    #[test]
+    fn test_outcome_ensures_02() {
+        // PUSH1 0x0; PUSH1 0x0; REVERT
+        let files = generate_hex("60006000fd",&[]);
+        assert!(files["test_0_main.dfy"].contains("ensures st''.IsRevert()"));
+        assert!(!files["test_0_main.dfy"].contains("ensures st''.RETURNS?"));
+    }
+
+    #[test]
+    fn test_calldata_facts_01() {
+        // PUSH1 0x4; CALLDATASIZE; LT; PUSH1 0x8; JUMPI; STOP;
+        // JUMPDEST; PUSH1 0x0; DUP1; REVERT
+        let files = generate_hex("60043610600857005b600080fd",&[]);
+        let main = &files["test_0_main.dfy"];
+        // Fall-thru block gains the calldata lower bound
+        let fallthru = &main[main.find("method block_0_0x0007(").unwrap()..main.find("method block_0_0x0008(").unwrap()];
+        assert!(fallthru.contains("requires st'.evm.context.CallDataSize() >= 0x04"));
+        assert!(main.contains("requires st'.evm.context.CallDataSize() < 0x04"));
+    }
+
+    #[test]
+    fn test_parallel_groups_01() {
+        // Loop between 0x02 and 0x0b, exiting to 0x13
+        let settings = config(&["--group-by","scc"]);
+        let (_,contract) = load(b"600a5b8015601357600b565b600190036002565b00".to_vec(),&settings).unwrap();
+        let parallel = generate(&contract,&settings).unwrap();
+        // Writing groups on a single thread gives identical files
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let sequential = pool.install(|| generate(&contract,&settings).unwrap());
+        assert!(parallel.len() > 2);
+        assert_eq!(parallel,sequential);
+    }
+
+    #[test]
+    fn test_stdin_01() {
+        // Prefix is taken from the command-line when reading stdin
+        let matches = cli().get_matches_from(["devmpg","--prefix","piped","-"]);
+        let settings = configure(&matches).unwrap();
+        assert_eq!(settings.prefix,"piped");
+        let input = read_input("-",&mut &b"600160020100"[..]).unwrap();
+        let (bytes,_) = load(input,&settings).unwrap();
+        assert_eq!(bytes,vec![0x60,0x01,0x60,0x02,0x01,0x00]);
+    }
+
+    #[test]
+    fn test_stdin_02() {
+        // Default prefix is used when none is given
+        let matches = cli().get_matches_from(["devmpg","--binary","-"]);
+        let settings = configure(&matches).unwrap();
+        assert_eq!(settings.prefix,DEFAULT_PREFIX);
+        let input = read_input("-",&mut &b"\x60\x01\x60\x02\x01\x00"[..]).unwrap();
+        let (_,contract) = load(input,&settings).unwrap();
+        let files = generate(&contract,&settings).unwrap();
+        assert!(files.contains_key("main_0_main.dfy"));
+    }
+
+    #[test]
+    fn test_binary_01() {
+        // PUSH1 0x1; PUSH1 0x2; ADD; STOP (as raw bytes)
+        let settings = config(&["--binary"]);
+        let raw = vec![0x60,0x01,0x60,0x02,0x01,0x00];
+        let (bytes,contract) = load(raw.clone(),&settings).unwrap();
+        assert_eq!(bytes,raw);
+        let files = generate(&contract,&settings).unwrap();
+        assert!(files["test_0_main.dfy"].contains("st := Add(st);"));
+    }
+
+    #[test]
+    fn test_decreases_01() {
+        // Loop between 0x02 and 0x0b, exiting to 0x13
+        let files = generate_hex("600a5b8015601357600b565b600190036002565b00",&["--decreases","st'.Peek(0)"]);
+        let main = &files["test_0_main.dfy"];
+        let entry = &main[main.find("method block_0_0x0000(").unwrap()..main.find("method block_0_0x0002(").unwrap()];
+        let header = &main[main.find("method block_0_0x0002(").unwrap()..];
+        assert!(!entry.contains("decreases"));
+        assert!(header.contains("decreases st'.Peek(0)"));
+    }
+
+    #[test]
+    fn test_decreases_02() {
+        // Gas is not a supported measure
+        let matches = cli().get_matches_from(vec!["devmpg","--decreases","gas","test.hex"]);
+        let err = configure(&matches).err().unwrap();
+        assert_eq!(err.to_string(),"--decreases gas is not supported (generated code does not charge gas)");
+    }
+
+    #[test]
+    fn test_single_file_01() {
+        // Two code sections, each with its own header
+        let settings = config(&["--single-file","--summary-json","summary.json"]);
+        let code = vec![PUSH(vec![0x1]),PUSH(vec![0x2]),ADD,STOP];
+        let contract = Assembly::new(vec![StructuredSection::Code(code.clone()),StructuredSection::Code(code)]);
+        let files = generate(&contract,&settings).unwrap();
+        let single = &files["test.dfy"];
+        assert_eq!(single.matches("include \"evm-dafny/src/dafny/evm.dfy\"").count(),1);
+        assert_eq!(single.matches("module Header_0 {").count(),1);
+        assert_eq!(single.matches("module Header_1 {").count(),1);
+        assert!(!single.contains("module Header {"));
+        // Modules are declared before being imported
+        let main = single.find("module main {").unwrap();
+        assert!(single.find("module Header_0 {").unwrap() < main);
+        assert!(single[main..].contains("import opened Header_0"));
+        assert!(single.contains("import opened Header_1"));
+        // Summary is still generated
+        assert!(files["summary.json"].contains("\"test.dfy\""));
+    }
+
+    #[test]
+    fn test_indent_01() {
+        // PUSH1 0x1; PUSH1 0x2; ADD; STOP
+        let files = generate_hex("600160020100",&["--indent-spaces","2"]);
+        for (f,contents) in &files {
+            assert!(!contents.contains('\t'),"tab in {f}");
+        }
+        assert!(files["test_0_main.dfy"].contains("\n  import opened Header\n"));
+        assert!(files["test_0_main.dfy"].contains("\n    st := Add(st);"));
+        assert!(files["test_0_header.dfy"].contains("\n  const BYTECODE_0_0 : seq<u8> := [\n    0x60"));
+    }
+
+    #[test]
+    fn test_pc_comments_01() {
+        // PUSH1 0x4; CALLDATASIZE; LT; PUSH1 0x8; JUMPI; STOP;
+        // JUMPDEST; PUSH1 0x0; DUP1; REVERT
+        let files = generate_hex("60043610600857005b600080fd",&["--pc-comments"]);
+        let main = &files["test_0_main.dfy"];
+        let entry = &main[main.find("method block_0_0x0000(").unwrap()..main.find("method block_0_0x0007(").unwrap()];
+        let target = &main[main.find("method block_0_0x0008(").unwrap()..];
+        // First instruction of each block shows its absolute offset
+        assert!(entry.contains("// 0x0000: "));
+        assert!(entry.contains("// 0x0002: "));
+        assert!(target.contains("// 0x0008: "));
+        assert!(target.contains("// 0x0009: "));
+        assert!(!target.contains("// 0x0000: "));
+    }
+
+    #[test]
+    fn test_unresolved_jump_01() {
+        // PUSH1 0x0; CALLDATALOAD; JUMP
+        let files = generate_hex("60003556",&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("// UNRESOLVED JUMP\n    st := Jump(st);\n    return st;"));
+    }
+
+    #[test]
+    fn test_unresolved_jump_02() {
+        // The (still executing) state is returned in functional mode
+        let files = generate_hex("60003556",&["--functional"]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("// UNRESOLVED JUMP\n    var st3 := Jump(st2);\n    st3\n"));
+    }
+
+    #[test]
+    fn test_transient_01() {
+        // PUSH1 0x2a; PUSH1 0x0; TSTORE; PUSH1 0x0; TLOAD; PUSH1 0xb;
+        // JUMP; JUMPDEST; STOP
+        let files = generate_hex("602a60005d60005c600b565b00",&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("st := TStore(st);"));
+        assert!(main.contains("st := TLoad(st);"));
+        let exit = &main[main.find("method block_0_0x000b(").unwrap()..];
+        assert!(exit.contains("requires st'.Operands() == 1"));
+    }
+
+    #[test]
+    fn test_mcopy_01() {
+        // PUSH1 0x20; PUSH1 0x0; PUSH1 0x40; MCOPY; STOP
+        let files = generate_hex("6020600060405e00",&[]);
+        assert!(files["test_0_main.dfy"].contains("st := MCopy(st);"));
+    }
+
+    #[test]
+    fn test_external_call_01() {
+        // Custom body replaces the dummy implementation
+        let mut settings = config(&[]);
+        settings.external_call = Some("\t\treturn EvmState.ERROR(EvmState.REVERTS); // custom\n".to_string());
+        let (_,contract) = load(b"600160020100".to_vec(),&settings).unwrap();
+        let files = generate(&contract,&settings).unwrap();
+        let header = &files["test_0_header.dfy"];
+        assert!(header.contains("method external_call(sender: u160, st: EvmState.ExecutingState)"));
+        assert!(header.contains("return EvmState.ERROR(EvmState.REVERTS); // custom"));
+        assert!(!header.contains("// dummy"));
+    }
+
+    #[test]
+    fn test_external_call_02() {
+        // Stub omitted entirely
+        let files = generate_hex("600160020100",&["--no-external-call"]);
+        assert!(!files["test_0_header.dfy"].contains("external_call"));
+    }
+
+    #[test]
+    fn test_gas_bounds_01() {
+        // PUSH1 0x1; PUSH1 0x2; ADD; STOP
+        let files = generate_hex("600160020100",&["--gas-bounds"]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("// GAS: at least 9 (static)"));
+        // Gas is not charged by the generated code
+        assert!(!main.contains("Gas()"));
+    }
+
+    #[test]
+    fn test_stack_depth_01() {
+        // PUSH1 0x3; JUMP; JUMPDEST; DUP16; STOP
+        let files = generate_hex("6003565b8f00",&[]);
+        let main = &files["test_0_main.dfy"];
+        let shallow = &main[main.find("method block_0_0x0003(").unwrap()..];
+        assert!(shallow.contains("requires st'.Operands() >= 16"));
+    }
+
+    #[test]
+    fn test_memory_words_01() {
+        // PUSH1 0x2a; PUSH1 0x0; MSTORE; PUSH1 0x8; JUMP; JUMPDEST; STOP
+        let files = generate_hex("602a6000526008565b00",&[]);
+        let main = &files["test_0_main.dfy"];
+        let exit = &main[main.find("method block_0_0x0008(").unwrap()..];
+        assert!(exit.contains("requires st'.MemSize() >= 0x20 && st'.Read(0x0) == 0x2a"));
+    }
+
+    #[test]
+    fn test_memory_words_02() {
+        // Zero values and arbitrary offsets are also known
+        // PUSH1 0x0; PUSH1 0x80; MSTORE; PUSH1 0x8; JUMP; JUMPDEST; STOP
+        let files = generate_hex("60006080526008565b00",&[]);
+        let main = &files["test_0_main.dfy"];
+        let exit = &main[main.find("method block_0_0x0008(").unwrap()..];
+        assert!(exit.contains("requires st'.MemSize() >= 0xa0 && st'.Read(0x80) == 0x00"));
+    }
+
+    #[test]
+    fn test_max_methods_01() {
+        // PUSH1 0x3; JUMP; JUMPDEST; PUSH1 0x7; JUMP; JUMPDEST; STOP
+        let files = generate_hex("6003565b6007565b00",&["--max-methods-per-file","2"]);
+        assert!(!files.contains_key("test_0_main.dfy"));
+        let first = &files["test_0_main_0.dfy"];
+        let second = &files["test_0_main_1.dfy"];
+        assert_eq!(first.matches("method block_").count(),2);
+        assert_eq!(second.matches("method block_").count(),1);
+        // Later parts include and import earlier ones
+        assert!(second.contains("include \"test_0_main_0.dfy\""));
+        assert!(second.contains("import opened main_0\n"));
+        assert!(!first.contains("test_0_main_1.dfy"));
+        assert_acyclic(&files);
+    }
+
+    #[test]
+    fn test_width_requires_01() {
+        // PUSH1 0x0; CALLDATALOAD; PUSH20 MASK_U160; AND; PUSH1 0x1c;
+        // JUMP; JUMPDEST; POP; STOP
+        let hex = format!("60003573{}16601c565b5000","ff".repeat(20));
+        let files = generate_hex(&hex,&[]);
+        let main = &files["test_0_main.dfy"];
+        let target = &main[main.find("method block_0_0x001c(").unwrap()..];
+        assert!(target.contains("requires st'.Peek(0) <= MAX_U160"));
+        // Unmasked items gain no bound
+        assert!(!main.contains("requires st'.Peek(1) <= "));
+    }
+
+    #[test]
+    fn test_analysis_json_01() {
+        // PUSH1 0x4; JUMP; STOP; JUMPDEST; STOP
+        let files = generate_hex("600456005b00",&["--json","analysis.json"]);
+        let sections : Vec<SectionAnalysis> = serde_json::from_str(&files["analysis.json"]).unwrap();
+        // Round trips to identical output
+        assert_eq!(serde_json::to_string_pretty(&sections).unwrap(),files["analysis.json"]);
+        // One entry per block
+        assert_eq!(sections.len(),1);
+        let pcs : Vec<usize> = sections[0].blocks.iter().map(|b| b.pc).collect();
+        assert_eq!(pcs,vec![0x0,0x3,0x4]);
+        assert_eq!(sections[0].blocks[0].group,"main");
+        assert!(!sections[0].blocks[1].reachable);
+        assert!(sections[0].blocks[2].reachable);
+    }
+
+    #[test]
+    fn test_code_sections_01() {
+        // Two code sections, each with its own entry
+        let settings = config(&[]);
+        let contract = Assembly::new(vec![
+            StructuredSection::Code(vec![PUSH(vec![0x1]),POP,STOP]),
+            StructuredSection::Code(vec![PUSH(vec![0x2]),POP,STOP])
+        ]);
+        let files = generate(&contract,&settings).unwrap();
+        // Each section is rooted in its own group
+        assert!(files["test_0_main.dfy"].contains("method block_0_0x0000("));
+        assert!(files["test_1_main_1.dfy"].contains("method block_1_0x0000("));
+        assert!(!files["test_0_main.dfy"].contains("block_1_0x0000"));
+        assert!(!files.keys().any(|f| f.contains("util")));
+    }
+
+    #[test]
+    fn test_split_sections_01() {
+        // Split file naming a root in the second code section
+        let split = std::env::temp_dir().join("devmpg_test_split_sections_01.json");
+        fs::write(&split,r#"{"functions": [{"name": "main", "cid": 0, "pc": 0}, {"name": "foo", "cid": 1, "pc": 3}]}"#).unwrap();
+        let settings = config(&["--split",split.to_str().unwrap()]);
+        assert_eq!(settings.roots.get(&(1,3)).map(|s| s.as_str()),Some("foo"));
+        assert!(!settings.roots.contains_key(&(0,3)));
+        // PUSH1 0x3; JUMP; JUMPDEST; STOP (in second section)
+        let contract = Assembly::new(vec![
+            StructuredSection::Code(vec![STOP]),
+            StructuredSection::Code(vec![PUSH(vec![0x3]),JUMP,JUMPDEST,STOP])
+        ]);
+        let files = generate(&contract,&settings).unwrap();
+        assert!(files["test_1_foo.dfy"].contains("method block_1_0x0003("));
+        assert!(!files.contains_key("test_0_foo.dfy"));
+    }
+
+    #[test]
+    fn test_split_sections_02() {
+        // Split file in the map shape roots the first code section
+        let split = std::env::temp_dir().join("devmpg_test_split_sections_02.json");
+        fs::write(&split,r#"{"functions": {"main": "0x0", "foo": "0x3"}}"#).unwrap();
+        let settings = config(&["--split",split.to_str().unwrap()]);
+        assert_eq!(settings.roots.get(&(0,3)).map(|s| s.as_str()),Some("foo"));
+        assert_eq!(settings.roots.get(&(0,0)).map(|s| s.as_str()),Some("main"));
+    }
+
+    #[test]
+    fn test_jumpdest_requires_01() {
+        // PUSH1 0x3; JUMP; JUMPDEST; STOP
+        let files = generate_hex("6003565b00",&[]);
+        assert!(files["test_0_main.dfy"].contains("assume {:axiom} st.IsJumpDest(0x3);"));
+        assert!(!files["test_0_main.dfy"].contains("requires st'.IsJumpDest(0x3)"));
+        // Caller no longer assumes, as the target requires it
+        let files = generate_hex("6003565b00",&["--jumpdest-requires"]);
+        let main = &files["test_0_main.dfy"];
+        let caller = &main[main.find("method block_0_0x0000(").unwrap()..main.find("method block_0_0x0003(").unwrap()];
+        let target = &main[main.find("method block_0_0x0003(").unwrap()..];
+        assert!(!caller.contains("IsJumpDest"));
+        assert!(target.contains("requires st'.IsJumpDest(0x3)"));
+    }
+
+    #[test]
+    fn test_deadcode_01() {
+        // PUSH1 0x0; DUP1; REVERT; PUSH1 0x1; STOP
+        let settings = config(&["--report-deadcode"]);
+        let (_,contract) = load(b"600080fd600100".to_vec(),&settings).unwrap();
+        let cfgs = deconstruct(&contract,&settings);
+        let dead : Vec<(usize,usize)> = deadcode(&cfgs).iter().map(|(c,b)| (*c,b.pc())).collect();
+        assert_eq!(dead,vec![(0,0x4)]);
+    }
+
+    #[test]
+    fn test_dry_run_01() {
+        // Nothing is written, and the output directory is not created
+        let dir = std::env::temp_dir().join("devmpg_test_dry_run_01");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("test.hex");
+        fs::write(&target,"600160020100").unwrap();
+        let outdir = dir.join("out");
+        let settings = config(&["--dry-run","--outdir",outdir.to_str().unwrap()]);
+        run(target.to_str().unwrap(),&settings).unwrap();
+        assert!(!outdir.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(),1);
+    }
+
+    #[test]
+    fn test_dry_run_02() {
+        // Plan lists exactly the files generated
+        let settings = config(&["--dry-run"]);
+        let (_,contract) = load(b"600160020100".to_vec(),&settings).unwrap();
+        let groups = analyse(&contract,&settings).unwrap();
+        let files = render(&contract,&groups,&settings).unwrap();
+        assert_eq!(plan(&files,&groups),["test_0_header.dfy","test_0_main.dfy (1 block(s))","2 file(s) in 1 group(s)"]);
+        // Single file
+        let settings = config(&["--dry-run","--single-file"]);
+        let groups = analyse(&contract,&settings).unwrap();
+        let files = render(&contract,&groups,&settings).unwrap();
+        assert_eq!(plan(&files,&groups),["test.dfy (1 block(s))","1 file(s) in 1 group(s)"]);
+        // Aggregate file included
+        let settings = config(&["--dry-run","--include-guard"]);
+        let groups = analyse(&contract,&settings).unwrap();
+        let files = render(&contract,&groups,&settings).unwrap();
+        assert!(plan(&files,&groups).contains(&"test.dfy".to_string()));
+    }
+
+    #[test]
+    fn test_push_01() {
+        // PUSH20 0x11..11; POP; STOP
+        let hex = format!("73{}5000","11".repeat(20));
+        let files = generate_hex(&hex,&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains(&format!("st := Push20(st,0x{});","11".repeat(20))));
+        assert!(!main.contains("PushN("));
+    }
+
+    #[test]
+    fn test_selector_facts_01() {
+        // PUSH1 0x0; CALLDATALOAD; PUSH1 0x6; JUMP; JUMPDEST; DUP1;
+        // PUSH4 0x12345678; EQ; PUSH1 0x12; JUMPI; STOP; JUMPDEST; STOP
+        let files = generate_hex("6000356006565b80631234567814601257005b00",&[]);
+        let main = &files["test_0_main.dfy"];
+        // Handler gains the selector equality
+        let handler = &main[main.find("method block_0_0x0012(").unwrap()..];
+        assert!(handler.contains("requires st'.Peek(0) == 0x12345678"));
+        // Fall-thru gains the selector disequality
+        let fallthru = &main[main.find("method block_0_0x0011(").unwrap()..main.find("method block_0_0x0012(").unwrap()];
+        assert!(fallthru.contains("requires st'.Peek(0) != 0x12345678"));
+    }
+
+    #[test]
+    fn test_unsupported_01() {
+        // Any opcode byte not supported is stubbed out, rather than
+        // crashing.
+        let settings = config(&["--binary"]);
+        for b in 0..=255u8 {
+            let mut bytes = vec![0x60,0x01,b];
+            bytes.extend([0x0; 33]);
+            let (_,contract) = load(bytes,&settings).unwrap();
+            let insn = match contract.iter().next() {
+                Some(StructuredSection::Code(insns)) => insns[1].clone(),
+                _ => { continue; }
+            };
+            if block::is_supported(&insn) { continue; }
+            let files = generate(&contract,&settings).unwrap();
+            let main = &files["test_0_main.dfy"];
+            assert!(main.contains(&format!("UNSUPPORTED {insn}")),"{insn} not stubbed");
+            assert!(main.contains("assert false"));
+        }
+    }
+
+    #[test]
+    fn test_callf_01() {
+        // CALLF 1; STOP (in first section) and RETF (in second)
+        let settings = config(&[]);
+        let contract = Assembly::new(vec![
+            StructuredSection::Code(vec![CALLF(1),STOP]),
+            StructuredSection::Code(vec![RETF])
+        ]);
+        let files = generate(&contract,&settings).unwrap();
+        let main = &files["test_0_main.dfy"];
+        // Call into entry block of second section, continuing only
+        // if it returned.
+        let call = main.find("st := block_1_0x0000(st);").unwrap();
+        let check = main.find("if !st.EXECUTING? { return st; }").unwrap();
+        let stop = main.find("st := Stop(st);").unwrap();
+        assert!(call < check && check < stop);
+        assert!(main.contains("include \"test_1_main_1.dfy\""));
+        assert!(main.contains("import opened main_1\n"));
+        // Function returns the (still executing) state
+        let callee = &files["test_1_main_1.dfy"];
+        let body = &callee[callee.find("method block_1_0x0000(").unwrap()..];
+        assert!(body.contains("// Return to caller\n"));
+        assert!(body.contains("return st;"));
+        assert!(!body.contains("assert false"));
+        // Headers of distinct sections do not clash
+        assert!(files["test_1_header.dfy"].contains("module Header_1 {"));
+        assert!(callee.contains("import opened Header_1\n"));
+        assert_acyclic(&files);
+    }
+
+    #[test]
+    fn test_writes_permitted_01() {
+        // PUSH1 0x1; PUSH1 0x2; ADD; STOP
+        let files = generate_hex("600160020100",&[]);
+        assert!(!files["test_0_main.dfy"].contains("WritesPermitted"));
+        assert!(files["test_0_main.dfy"].contains("requires st'.PC() == 0x0000"));
+    }
+
+    #[test]
+    fn test_writes_permitted_02() {
+        // PUSH1 0x1; PUSH1 0x0; SSTORE; STOP
+        let files = generate_hex("600160005500",&[]);
+        assert!(files["test_0_main.dfy"].contains("requires st'.WritesPermitted() && st'.PC() == 0x0000"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_01() {
+        use std::os::unix::fs::PermissionsExt;
+        // Stand-in for Dafny which records its arguments
+        let dir = std::env::temp_dir().join("devmpg_test_verify_01");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("args.txt");
+        let bin = dir.join("dafny");
+        fs::write(&bin,format!("#!/bin/sh\necho \"$@\" >> {}\n[ \"$2\" != bad.dfy ]\n",log.display())).unwrap();
+        fs::set_permissions(&bin,fs::Permissions::from_mode(0o755)).unwrap();
+        let settings = config(&["--dafny",bin.to_str().unwrap()]);
+        // Each file verified in turn
+        verify(&["a.dfy".to_string(),"b.dfy".to_string()],&settings).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(),"verify a.dfy\nverify b.dfy\n");
+        // Failures are aggregated
+        let r = verify(&["bad.dfy".to_string(),"c.dfy".to_string()],&settings);
+        assert_eq!(r.unwrap_err().to_string(),"1 of 2 file(s) failed verification");
+        assert!(fs::read_to_string(&log).unwrap().ends_with("verify bad.dfy\nverify c.dfy\n"));
+    }
+
+    #[test]
+    fn test_create_01() {
+        // PUSH1 0x0; PUSH1 0x0; PUSH1 0x0; CREATE; POP; STOP
+        let files = generate_hex("600060006000f05000",&[]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("var CONTINUING(cc) := Create(st);"));
+        assert!(main.contains("var inner := cc.CreateEnter(1,address,cc.initcode);"));
+        assert!(main.contains("st := cc.CreateReturn(inner,address);"));
+        assert!(!main.contains("st := Create(st);"));
+    }
+
+    #[test]
+    fn test_selfdestruct_01() {
+        // PUSH1 0x0; SELFDESTRUCT
+        let files = generate_hex("6000ff",&[]);
+        let main = &files["test_0_main.dfy"];
+        let destruct = main.find("st := SelfDestruct(st);").unwrap();
+        let check = main.find("assert !st.EXECUTING?;").unwrap();
+        assert!(destruct < check);
+        assert!(main[check..].contains("return st;"));
+    }
+
+    #[test]
+    fn test_mask_hints_01() {
+        // PUSH1 0x0; CALLDATALOAD; PUSH20 MASK_U160; AND; POP; STOP
+        let hex = format!("60003573{}165000","ff".repeat(20));
+        let files = generate_hex(&hex,&["--masks","--mask-hints"]);
+        let main = &files["test_0_main.dfy"];
+        let mask = main.find("st := AndU160(st);").unwrap();
+        let hint = main.find("assert st.EXECUTING? ==> st.Peek(0) <= MAX_U160;").unwrap();
+        assert!(mask < hint);
+        // No hints unless requested
+        let files = generate_hex(&hex,&["--masks"]);
+        assert!(files["test_0_main.dfy"].contains("st := AndU160(st);"));
+        assert!(!files["test_0_main.dfy"].contains("MAX_U160;"));
+    }
+
+    #[test]
+    fn test_dedup_01() {
+        // PUSH1 0x0; CALLDATALOAD; PUSH1 0x9; JUMPI; PUSH1 0xb; JUMP;
+        // JUMPDEST; STOP; JUMPDEST; STOP
+        let hex = "600035600957600b565b005b00";
+        let files = generate_hex(hex,&["--dedup"]);
+        let main = &files["test_0_main.dfy"];
+        assert!(main.contains("method block_0_0x0009("));
+        assert!(!main.contains("method block_0_0x000b("));
+        assert!(!main.contains("block_0_0x000b(st)"));
+    }
+
+    #[test]
+    fn test_dedup_02() {
+        // Root at a duplicated block remains addressable
+        let split = std::env::temp_dir().join("devmpg_test_dedup_02.json");
+        fs::write(&split,r#"{"functions": {"main": "0x0", "foo": "0xb"}}"#).unwrap();
+        let split = split.to_str().unwrap();
+        let files = generate_hex("600035600957600b565b005b00",&["--dedup","--split",split]);
+        assert!(files["test_0_main.dfy"].contains("method block_0_0x0009("));
+        assert!(files["test_0_foo.dfy"].contains("method block_0_0x000b("));
+    }
+
+    #[test]
+    fn test_reachability_lemma_01() {
+        // PUSH1 0x3; JUMP; JUMPDEST; STOP
+        let files = generate_hex("6003565b00",&["--reachability-lemma"]);
+        let main = &files["test_0_main.dfy"];
+        let lemma = &main[main.find("lemma reachable_").unwrap()..];
+        assert!(lemma.contains("// block_0_0x0000"));
+        assert!(lemma.contains("// block_0_0x0003"));
+        assert!(lemma.contains("assume {:axiom} exists st': EvmState.ExecutingState :: st'.PC() == 0x3 && (st'.Operands() in {0});"));
+        // Not emitted by default
+        let files = generate_hex("6003565b00",&[]);
+        assert!(!files["test_0_main.dfy"].contains("lemma reachable_"));
+    }
+}
@@ -93,7 +93,7 @@ pub static OPCODES: &'static [&'static str] = &[
     "JumpDest",       //         0x5b
     "TLoad",          //                 0x5c
     "TStore",         //                 0x5d
-    "",               //                 0x5e
+    "MCopy",          //                 0x5e
     "Push0",          //                 0x5f
     "Push1",          //            0x60
     "Push2",          //            0x61
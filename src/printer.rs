@@ -5,7 +5,9 @@ use evmil::util::{ToHexString,w256};
 
 use crate::Config;
 use crate::block::{Bytecode,Block,BlockState};
+use crate::refine::StackValue;
 use crate::analysis::*;
+use crate::gas::static_cost;
 use crate::opcodes::{OPCODES};
 
 /// Responsible for printing individual blocks to a given writer.
@@ -15,88 +17,472 @@ use crate::opcodes::{OPCODES};
 pub struct BlockPrinter<'a,T:Write> {
     id: usize,
     out: T,
-    settings: &'a Config
+    settings: &'a Config,
+    /// Number of state updates printed so far in the current block
+    /// (used only in functional mode).
+    step: usize,
+    /// Jump targets of the current block known to begin with a
+    /// `JUMPDEST`.
+    jumpdests: Vec<usize>
 }
 
 impl<'a,T:Write> BlockPrinter<'a,T> {
     pub fn new(id: usize, out: T, settings: &'a Config) -> Self {
-        Self{id,out,settings}
+        Self{id,out,settings,step:0,jumpdests:Vec::new()}
     }
     
-    pub fn print_block(&mut self, block: &Block) {
-        // Print method signature
-        writeln!(self.out,"\tmethod block_{}_{:#06x}(st': EvmState.ExecutingState) returns (st'': EvmState.State)", self.id, block.pc());
-        // Print standard requires
-        writeln!(self.out,"\trequires st'.evm.code == Code.Create(BYTECODE_{})",self.id);
-        writeln!(self.out,"\trequires st'.WritesPermitted() && st'.PC() == {:#06x}",block.pc());
-        if block.is_unreachable() {
-            // Deadcode
-            writeln!(self.out,"\t// Deadcode");            
-            writeln!(self.out,"\trequires false");
+    pub fn print_block(&mut self, block: &Block) -> std::io::Result<()> {
+        self.step = 0;
+        self.jumpdests = block.jumpdest_targets().to_vec();
+        // Print method (or function) signature
+        let isolate = *self.settings.isolate_overrides.get(&block.pc()).unwrap_or(&self.settings.isolate_assertions);
+        let attr = if isolate { "{:isolate_assertions} " } else { "" };
+        if self.settings.functional {
+            writeln!(self.out,"{}function {attr}block_{}_{:#06x}(st': EvmState.ExecutingState): (st'': EvmState.State)",self.indent(0), self.id, block.pc())?;
         } else {
-            self.print_fmp_requires(block);
-            self.print_stack_requires(block);
+            writeln!(self.out,"{}method {attr}block_{}_{:#06x}(st': EvmState.ExecutingState) returns (st'': EvmState.State)",self.indent(0), self.id, block.pc())?;
+        }
+        // Print specification (unless disabled)
+        if !self.settings.no_std_requires {
+            self.print_requires(block)?;
         }
-        writeln!(self.out,"\t{{");
-        writeln!(self.out,"\t\tvar st := st';");
+        self.print_decreases(block)?;
+        self.print_comment(0,&format!("STACK DELTA: {:+}",block.stack_delta()))?;
+        self.print_coverage(block)?;
+        self.print_owners(block)?;
+        if block.is_loop_header() { self.print_comment(0,"LOOP HEADER")?; }
+        writeln!(self.out,"{}{{",self.indent(0))?;
+        writeln!(self.out,"{}var {} := st';",self.indent(1),self.var())?;
+        if self.settings.opaque_bytecode && block.needs_code() {
+            writeln!(self.out,"{}reveal BYTECODE_{}();",self.indent(1),self.id)?;
+        }
+        let mut pc = block.pc();
+        // Determine whether free memory pointer reads should be related
+        let mut fmp_stable = self.settings.fmp_stable && !block.is_unreachable();
+        fmp_stable &= block.fmp_reads() > 1 && !block.may_write_fmp();
+        let mut fmp_read = false;
         for (i,code) in block.iter().enumerate() {
             let state = block.state(i);
-            self.print_debug_info(state);
-            self.print_code(code,state);
+            self.print_debug_info(state)?;
+            self.print_pc_comment(code,pc)?;
+            if block.stack_underflow() == Some(pc) && code.length() > 0 {
+                // Always printed, even in compact mode
+                writeln!(self.out,"{}// STACK UNDERFLOW at {pc:#x}",self.indent(1))?;
+            }
+            self.print_code(block,code,state,pc)?;
+            pc += code.length();
+            if fmp_stable && block.is_fmp_read(i) {
+                self.print_fmp_stable(fmp_read)?;
+                fmp_read = true;
+            }
         }
+        let v = self.var();
         match block.next() {
+            Some(pc) if self.settings.functional => {
+                writeln!(self.out,"{}block_{}_{:#06x}({v})",self.indent(1),self.id,block.callee(pc))?;
+            }
             Some(pc) => {
-                writeln!(self.out,"\t\tst := block_{}_{pc:#06x}(st);",self.id);
-                writeln!(self.out,"\t\treturn st;");                
+                writeln!(self.out,"{}{v} := block_{}_{:#06x}({v});",self.indent(1),self.id,block.callee(pc))?;
+                writeln!(self.out,"{}return {v};",self.indent(1))?;                
+            }
+            None if self.settings.functional => {
+                writeln!(self.out,"{}{v}",self.indent(1))?;
             }
             None => {
-                writeln!(self.out,"\t\treturn st;");
+                writeln!(self.out,"{}return {v};",self.indent(1))?;
             }
         }
-        writeln!(self.out,"\t}}");
-        writeln!(self.out,"");        
+        writeln!(self.out,"{}}}",self.indent(0))?;
+        writeln!(self.out,"")?;        
+        Ok(())
+    }
+
+    /// Print a lemma stub enumerating, for each (reachable) block, the
+    /// obligation that its entry stack heights are satisfiable from
+    /// the entry block.  Each obligation is an `assume` placeholder,
+    /// to be replaced by a proof.
+    pub fn print_reachability_lemma(&mut self, name: &str, blocks: &[&Block]) -> std::io::Result<()> {
+        writeln!(self.out,"{}lemma reachable_{name}()",self.indent(0))?;
+        writeln!(self.out,"{}{{",self.indent(0))?;
+        for block in blocks.iter().filter(|b| !b.is_unreachable()) {
+            let pc = block.pc();
+            let heights = match height_clauses(&block.stack_heights()) {
+                h if h.is_empty() => "true".to_string(),
+                h => h
+            };
+            writeln!(self.out,"{}// block_{}_{pc:#06x}",self.indent(1),self.id)?;
+            writeln!(self.out,"{}assume {{:axiom}} exists st': EvmState.ExecutingState :: st'.PC() == {pc:#x} && ({heights});",self.indent(1))?;
+        }
+        writeln!(self.out,"{}}}",self.indent(0))?;
+        Ok(())
     }
 
-    fn print_fmp_requires(&mut self, block: &Block) {
+    fn print_fmp_requires(&mut self, block: &Block) -> std::io::Result<()> {
+	let mut block = block.clone();
+	// Minimise block information (if applicable)
+	if self.settings.minimise_requires {
+	    block.minimise();
+	} else if self.settings.minimise_memory {
+	    block.minimise_memory();
+	}
         // Constants to help
         let fmps = block.freemem_ptrs();
         // Generic free ptr bounds
         match fmps {
             Some((v,w)) => {
                 if v >= 0x60 {
-                    writeln!(self.out,"\t// Free memory pointer");                    
-                    write!(self.out,"\trequires st'.MemSize() >= 0x60 && ");                
+                    self.print_comment(0,"Free memory pointer")?;
+                    write!(self.out,"{}requires st'.MemSize() >= 0x60 && ",self.indent(0))?;                
                     if v == w {
-                        writeln!(self.out,"st'.Read(0x40) == {:#02x}",v);
+                        writeln!(self.out,"st'.Read(0x40) == {:#02x}",v)?;
                     } else {
-                        writeln!(self.out,"st'.Read(0x40) >= {:#02x}",v);
+                        writeln!(self.out,"st'.Read(0x40) >= {:#02x}",v)?;
                     }
                 }
             }
             _ => {}
         }        
+        // Known memory words
+        let words = block.memory_words();
+        if words.len() > 0 {
+            self.print_comment(0,"Known memory words")?;
+        }
+        for (off,v) in words {
+            write!(self.out,"{}requires st'.MemSize() >= {:#02x} && st'.Read({off:#02x}) == ",self.indent(0),off+0x20)?;
+            self.write_w256(&v)?;
+            writeln!(self.out)?;
+        }
+        Ok(())
     }
     
-    fn print_stack_requires(&mut self, block: &Block) {
+    /// Print the storage slots known on entry to a block, as these
+    /// may be needed to establish values loaded from them.
+    fn print_storage_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        let slots = block.storage_slots();
+        if slots.len() > 0 {
+            self.print_comment(0,"Known storage slots")?;
+        }
+        for (k,v) in slots {
+            write!(self.out,"{}requires st'.Load(",self.indent(0))?;
+            self.write_w256(&k)?;
+            write!(self.out,") == ")?;
+            self.write_w256(&v)?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    /// Get the indentation for a given nesting level, where level `0`
+    /// is that of a method (within its enclosing module).
+    fn indent(&self, level: usize) -> String {
+        self.settings.indent.repeat(self.settings.base_level + level)
+    }
+
+    /// Get the name of the current state variable.  In functional
+    /// mode, every update binds a fresh (numbered) variable.
+    fn var(&self) -> String {
+        let v = &self.settings.state_var;
+        if self.settings.functional { format!("{v}{}",self.step) } else { v.clone() }
+    }
+
+    /// Print an update of the current state variable with a given
+    /// expression.
+    fn update(&mut self, rhs: &str) -> std::io::Result<()> {
+        if self.settings.functional {
+            self.step += 1;
+            writeln!(self.out,"{}var {} := {rhs};",self.indent(1),self.var())?;
+        } else {
+            writeln!(self.out,"{}{} := {rhs};",self.indent(1),self.var())?;
+        }
+        Ok(())
+    }
+
+    /// Relate a read of the free memory pointer to the first such
+    /// read in this block (which writes nothing to `0x40`).
+    fn print_fmp_stable(&mut self, subsequent: bool) -> std::io::Result<()> {
+        let v = self.var();
+        if subsequent {
+            writeln!(self.out,"{}assert {v}.Peek(0) == fmp;",self.indent(1))?;
+        } else if self.settings.functional {
+            writeln!(self.out,"{}var fmp := {v}.Peek(0);",self.indent(1))?;
+        } else {
+            writeln!(self.out,"{}ghost var fmp := {v}.Peek(0);",self.indent(1))?;
+        }
+        Ok(())
+    }
+
+    /// Print a requirement that there is no pending return data, for
+    /// blocks which cannot be entered after a call.
+    fn print_returndata_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        if self.settings.returndata_requires && block.is_call_free() {
+            writeln!(self.out,"{}requires st'.evm.context.ReturnDataSize() == 0",self.indent(0))?;
+        }
+        Ok(())
+    }
+
+    /// Print a lower bound on the calldata size for function roots
+    /// whose ABI signature is known.
+    fn print_calldata_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        if self.id != 0 { return Ok(()); }
+        if let Some(n) = self.settings.calldata_bounds.get(&block.pc()) {
+            self.print_comment(0,"ABI arguments")?;
+            writeln!(self.out,"{}requires st'.evm.context.CallDataSize() >= {n}",self.indent(0))?;
+        }
+        Ok(())
+    }
+
+    /// Print the standard specification for a block, which consists
+    /// of its `requires` (and `ensures`) clauses.
+    fn print_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        // Print standard requires
+        writeln!(self.out,"{}requires st'.evm.code == Code.Create(BYTECODE_{})",self.indent(0),self.id)?;
+        let pc = if block.aliases().len() == 0 {
+            format!("st'.PC() == {:#06x}",block.pc())
+        } else {
+            let pcs : Vec<String> = std::iter::once(block.pc()).chain(block.aliases().iter().copied()).map(|pc| format!("{pc:#06x}")).collect();
+            format!("st'.PC() in {{{}}}",pcs.join(","))
+        };
+        if block.may_write() {
+            writeln!(self.out,"{}requires st'.WritesPermitted() && {pc}",self.indent(0))?;
+        } else {
+            writeln!(self.out,"{}requires {pc}",self.indent(0))?;
+        }
+        if block.is_unreachable() {
+            // Deadcode
+            match block.unreachable_reason() {
+                Some(r) if self.settings.unreachable_comments => {
+                    self.print_comment(0,&format!("Deadcode ({r})"))?;
+                }
+                _ => { self.print_comment(0,"Deadcode")?; }
+            }
+            writeln!(self.out,"{}requires false",self.indent(0))?;
+        } else {
+            self.print_fmp_requires(block)?;
+            self.print_storage_requires(block)?;
+            self.print_stack_requires(block)?;
+            self.print_fact_requires(block)?;
+            self.print_width_requires(block)?;
+            self.print_jumpdest_requires(block)?;
+            self.print_fmp_alias_requires(block)?;
+            self.print_returndata_requires(block)?;
+            self.print_calldata_requires(block)?;
+            self.print_stack_delta_ensures(block)?;
+            self.print_outcome_ensures(block)?;
+            self.print_gas_bound(block)?;
+        }
+        Ok(())
+    }
+
+    /// Print a postcondition describing the net change in stack
+    /// height across this block.  This only applies to blocks which
+    /// return whilst still executing (e.g. at an unresolved jump),
+    /// since otherwise the final state is either halted or that of
+    /// some successor.
+    fn print_stack_delta_ensures(&mut self, block: &Block) -> std::io::Result<()> {
+        let branches = block.iter().any(|bc| {
+            matches!(bc,Bytecode::Jump(_)|Bytecode::JumpI(_)|Bytecode::RJump(_)|Bytecode::RJumpI(_)|Bytecode::Unit(JUMPI))
+        });
+        let executing = match block.iter().last() {
+            Some(Bytecode::Unit(DATA(_))) => false,
+            Some(Bytecode::Unit(insn)) => matches!(insn,JUMP) || insn.fallthru(),
+            _ => false
+        };
+        if branches || block.next().is_some() || !executing { return Ok(()); }
+        let delta = block.stack_delta();
+        if delta < 0 {
+            writeln!(self.out,"{}ensures st''.EXECUTING? ==> st''.Operands() == st'.Operands() - {}",self.indent(0),-delta)?;
+        } else {
+            writeln!(self.out,"{}ensures st''.EXECUTING? ==> st''.Operands() == st'.Operands() + {delta}",self.indent(0))?;
+        }
+        Ok(())
+    }
+
+    /// Print a termination measure for a block which participates in
+    /// a loop (if applicable).
+    fn print_decreases(&mut self, block: &Block) -> std::io::Result<()> {
+        if let (Some(m),true) = (&self.settings.decreases,block.is_in_loop()) {
+            writeln!(self.out,"{}decreases {m}",self.indent(0))?;
+        }
+        Ok(())
+    }
+
+    /// Print a comment giving a lower bound on the gas consumed by a
+    /// block, as determined from static gas costs.  Instructions with
+    /// dynamic costs are excluded from the bound.  This is not given as
+    /// a postcondition, since the generated code does not charge gas.
+    fn print_gas_bound(&mut self, block: &Block) -> std::io::Result<()> {
+        if !self.settings.gas_bounds { return Ok(()); }
+        let mut cost = 0;
+        let mut dynamic = Vec::new();
+        for bc in block.iter() {
+            let c = match bc {
+                Bytecode::Jump(_) => Some(8),
+                Bytecode::JumpI(_) => Some(10),
+                Bytecode::RJump(_) => Some(2),
+                Bytecode::RJumpI(_) => Some(4),
+                Bytecode::Mask(_) => Some(3),
+                Bytecode::Unit(insn) => {
+                    let c = static_cost(insn);
+                    if c.is_none() { dynamic.push(OPCODES[insn.opcode() as usize]); }
+                    c
+                }
+                _ => Some(0)
+            };
+            cost += c.unwrap_or(0);
+        }
+        if dynamic.len() > 0 {
+            dynamic.sort_unstable();
+            dynamic.dedup();
+            self.print_comment(0,&format!("Gas bound excludes dynamic costs ({})",dynamic.join(", ")))?;
+        }
+        if cost > 0 {
+            self.print_comment(0,&format!("GAS: at least {cost} (static)"))?;
+        }
+        Ok(())
+    }
+
+    /// Print a postcondition describing the outcome of a block which
+    /// ends in a terminating instruction (e.g. `RETURN`).
+    fn print_outcome_ensures(&mut self, block: &Block) -> std::io::Result<()> {
+        let branches = block.iter().any(|bc| {
+            matches!(bc,Bytecode::Jump(_)|Bytecode::JumpI(_)|Bytecode::RJump(_)|Bytecode::RJumpI(_))
+        });
+        if branches || block.next().is_some() { return Ok(()); }
+        let outcome = match block.iter().last() {
+            Some(Bytecode::Unit(STOP|RETURN|SELFDESTRUCT)) => "RETURNS?",
+            Some(Bytecode::Unit(REVERT)) => "IsRevert()",
+            Some(Bytecode::Unit(INVALID)) => "ERROR?",
+            _ => { return Ok(()); }
+        };
+        writeln!(self.out,"{}ensures st''.{outcome}",self.indent(0))?;
+        Ok(())
+    }
+
+    /// Print a comment naming the function(s) owning this block.
+    fn print_owners(&mut self, block: &Block) -> std::io::Result<()> {
+        match block.owners() {
+            (owners,_) if owners.len() == 0 => Ok(()),
+            (owners,false) => self.print_comment(0,&format!("Owned by: {}",owners.join(", "))),
+            (owners,true) => self.print_comment(0,&format!("Shared by: {}",owners.join(", ")))
+        }
+    }
+
+    /// Print a comment summarising how many distinct abstract states
+    /// reached this block, along with their stack heights.  This
+    /// gives a sense of the fan-in at join points.
+    fn print_coverage(&mut self, block: &Block) -> std::io::Result<()> {
+        let n = block.entry_states().len();
+        let heights : Vec<String> = block.stack_heights().iter().map(|h| h.to_string()).collect();
+        self.print_comment(0,&format!("COVERAGE: reached by {n} path(s), heights {{{}}}",heights.join(",")))?;
+        Ok(())
+    }
+
+    /// Print requirements for stack items known to fit within a given
+    /// width (i.e. which were masked).  Items masked to 160 bits are
+    /// addresses, and are bounded using `MAX_U160`.
+    fn print_width_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        let min = match block.stack_bounds() {
+            Some((min,_)) => min,
+            None => { return Ok(()); }
+        };
+        let mut first = true;
+        for (i,v) in block.stack_values().iter().enumerate() {
+            match v {
+                StackValue::Width(n) if i < min && *n > 0 && *n < 256 => {
+                    if first { self.print_comment(0,"Masked items")?; }
+                    if *n == 160 {
+                        writeln!(self.out,"{}requires st'.Peek({i}) <= MAX_U160",self.indent(0))?;
+                    } else {
+                        writeln!(self.out,"{}requires st'.Peek({i}) <= {}",self.indent(0),max_value(*n))?;
+                    }
+                    first = false;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the requirement that a block beginning with a `JUMPDEST`
+    /// is a valid jump destination (see `--jumpdest-requires`).  This
+    /// replaces the corresponding assumption in its callers.
+    fn print_jumpdest_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        if self.settings.jumpdest_requires && block.is_jumpdest() {
+            writeln!(self.out,"{}requires st'.IsJumpDest({:#x})",self.indent(0),block.pc())?;
+        }
+        Ok(())
+    }
+
+    /// Print requirements for stack items known to hold the current
+    /// value of the free memory pointer (e.g. as left by `PUSH 0x40;
+    /// MLOAD`), even when that value is itself unknown.  Items known
+    /// to hold the free memory pointer plus some constant offset are
+    /// related to the item with the smallest such offset.
+    fn print_fmp_alias_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        let min = match block.stack_bounds() {
+            Some((min,_)) => min,
+            None => { return Ok(()); }
+        };
+        let items : Vec<(usize,usize)> = block.stack_values().iter().enumerate().filter_map(|(i,v)| {
+            match v {
+                StackValue::FreeMemPtr(k) if i < min => Some((i,*k)),
+                _ => None
+            }
+        }).collect();
+        let base = match items.iter().min_by_key(|(_,k)| *k) {
+            Some(b) => *b,
+            None => { return Ok(()); }
+        };
+        let mut first = true;
+        for (i,k) in items {
+            if k != 0 && i == base.0 { continue; }
+            if first { self.print_comment(0,"Free memory pointer items")?; }
+            if k == 0 {
+                writeln!(self.out,"{}requires st'.Peek({i}) == st'.Read(0x40)",self.indent(0))?;
+            } else {
+                writeln!(self.out,"{}requires st'.Peek({i}) == st'.Peek({}) + {:#x}",self.indent(0),base.0,k - base.1)?;
+            }
+            first = false;
+        }
+        Ok(())
+    }
+
+    fn print_fact_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        if block.facts().len() > 0 {
+            self.print_comment(0,"Branch facts")?;
+            for f in block.facts() {
+                writeln!(self.out,"{}requires {f}",self.indent(0))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_stack_requires(&mut self, block: &Block) -> std::io::Result<()> {
+        // Nothing to say about unreachable blocks
+        if block.stack_bounds().is_none() { return Ok(()); }
 	let mut block = block.clone();
 	// Minimise block information (if applicable)
 	if self.settings.minimise_requires {
 	    block.minimise();
 	}
         // Generic stack bounds
-        writeln!(self.out,"\t// Stack height(s)");
-        self.print_stack_heights(&block);
+        self.print_comment(0,"Stack height(s)")?;
+        self.print_stack_heights(&block)?;
         // Determine constant items
         let join = block.entry_state();
         // Print static items
-        self.print_static_stack_requires(&join);
+        self.print_static_stack_requires(&join)?;
         // Print dynamic items
-        self.print_dynamic_stack_requires(&block,&join);
+        self.print_dynamic_stack_requires(&block,&join)?;
+        Ok(())
     }
 
-    fn print_stack_heights(&mut self, block: &Block) {
+    fn print_stack_heights(&mut self, block: &Block) -> std::io::Result<()> {
         // Compute min \& max heights
-        let (min,max) = block.stack_bounds();
+        let (min,max) = match block.stack_bounds() {
+            Some(bounds) => bounds,
+            None => { return Ok(()); }
+        };
         let heights = block.stack_heights();
         let mut contig = true;
         for i in 0..heights.len() {
@@ -104,72 +490,79 @@ impl<'a,T:Write> BlockPrinter<'a,T> {
         }
         //
         if min == max {
-            writeln!(self.out,"\trequires st'.Operands() == {min}");
+            writeln!(self.out,"{}requires st'.Operands() == {min}",self.indent(0))?;
         } else if contig {
-            writeln!(self.out,"\trequires st'.Operands() >= {min} && st'.Operands() <= {max}");
+            writeln!(self.out,"{}requires st'.Operands() >= {min} && st'.Operands() <= {max}",self.indent(0))?;
         } else {
-            write!(self.out,"\trequires st'.Operands() in {{");
-            for h in heights {
-                if h != min { write!(self.out,","); }
-                write!(self.out,"{h}");
-            }
-            writeln!(self.out,"}}");
+            writeln!(self.out,"{}requires {}",self.indent(0),height_clauses(&heights))?;
         }
+        // Check stack depth reached by the block itself
+        let required = block.required_operands();
+        if min < required {
+            println!("WARNING: block {:#x} requires {required} operands, but may have only {min}",block.pc());
+            writeln!(self.out,"{}requires st'.Operands() >= {required}",self.indent(0))?;
+        }
+        Ok(())
     }        
     
-    fn print_dynamic_stack_requires(&mut self, block: &Block, join: &AbstractState) {
-        let (min,max) = block.stack_bounds();
+    fn print_dynamic_stack_requires(&mut self, block: &Block, join: &AbstractState) -> std::io::Result<()> {
+        let (min,max) = match block.stack_bounds() {
+            Some(bounds) => bounds,
+            None => { return Ok(()); }
+        };
         // Decompose states        
         let stacked = block_stacked_states(block,join,max+1);        
         //
         for (sh,sts) in stacked.iter().enumerate() {
             if min <= sh && is_useful(&sts) {
-                if min == sh { writeln!(self.out,"\t// Dynamic stack items"); }                
-                write!(self.out,"\trequires ");
-                if min != max { write!(self.out,"st'.Operands() == {sh} ==> ("); }
+                if min == sh { self.print_comment(0,"Dynamic stack items")?; }
+                write!(self.out,"{}requires ",self.indent(0))?;
+                if min != max { write!(self.out,"st'.Operands() == {sh} ==> (")?; }
                 for (i,st) in sts.iter().enumerate() {
                     if i != 0 {
-                        write!(self.out," || ");
+                        write!(self.out," || ")?;
                     }
-                    self.print_state(st);
+                    self.print_state(st)?;
                 }
-                if min != max { write!(self.out,")"); }
-                writeln!(self.out,"");
+                if min != max { write!(self.out,")")?; }
+                writeln!(self.out,"")?;
             } 
         }
+        Ok(())
     }
 
     /// Print all static 
-    fn print_static_stack_requires(&mut self, join: &AbstractState) {
+    fn print_static_stack_requires(&mut self, join: &AbstractState) -> std::io::Result<()> {
         // Check whether at least one static stack item.
         let atleast_one = join.stack().iter().fold(false,|a,e| a || matches!(e,Some(_)));
         //
         if atleast_one {
-            writeln!(self.out,"\t// Static stack items");
-            write!(self.out,"\trequires ");
-            self.print_state(join);
-            writeln!(self.out);
+            self.print_comment(0,"Static stack items")?;
+            write!(self.out,"{}requires ",self.indent(0))?;
+            self.print_state(join)?;
+            writeln!(self.out)?;
         }
+        Ok(())
     }        
 
-    fn print_state(&mut self, state: &AbstractState) {
+    fn print_state(&mut self, state: &AbstractState) -> std::io::Result<()> {
         let stack = state.stack();
-        write!(self.out,"(");
+        write!(self.out,"(")?;
         // Print out stack
         let mut first = true;
         for i in 0..stack.len() {
             match stack[i] {
                 Some(v) => {
                     if !first {
-                        write!(self.out," && ");
+                        write!(self.out," && ")?;
                     }
                     // NOTE: following is a hack to work around
                     // hex display problems with w256.
                     if v.byte_len() <= 16 {
                         let jth128 : u128 = v.to();
-                        write!(self.out,"st'.Peek({i}) == {:#02x}",jth128);
+                        write!(self.out,"st'.Peek({i}) == {:#02x}",jth128)?;
                     } else {
-                        write!(self.out,"st'.Peek({i}) == {:#02x}",v);
+                        write!(self.out,"st'.Peek({i}) == {:#02x}",v)?;
                     }
                     first = false;                    
                 }
@@ -178,23 +571,29 @@ impl<'a,T:Write> BlockPrinter<'a,T> {
                 }
             }
         }
-        write!(self.out,")");        
+        write!(self.out,")")?;        
+        Ok(())
     }
 
     fn print_debug_info(&mut self, state: &BlockState) -> std::io::Result<()> {
+        // Debug information is non-essential
+        if self.settings.compact { return Ok(()); }
 	let mut state = state.clone();
 	// Minimiase this state (if applicable)
 	if self.settings.minimise_internal {
 	    state.minimise();
 	}
         for s in state.states() {
-            write!(self.out,"\t\t//");            
+            write!(self.out,"{}//",self.indent(1))?;            
             write!(self.out,"|")?;                
             // Write freemem ptr
             match s.freemem_ptr() {
-                Some(w) => { write!(self.out,"fp={w:#06x}"); }
+                Some(w) => { write!(self.out,"fp={w:#06x}")?; }
                 None => {}
             }       
+            if self.settings.debug && state.necessary_freemem_ptr() {
+                write!(self.out,"*")?;
+            }
             write!(self.out,"|")?;
             // Write stack
             for (i,av) in s.stack().iter().enumerate() {
@@ -212,6 +611,15 @@ impl<'a,T:Write> BlockPrinter<'a,T> {
         Ok(())        
     }
 
+    /// Print a (non-essential) comment at a given indentation, unless
+    /// operating in compact mode.
+    fn print_comment(&mut self, level: usize, s: &str) -> std::io::Result<()> {
+        if !self.settings.compact {
+            writeln!(self.out,"{}// {s}",self.indent(level))?;
+        }
+        Ok(())
+    }
+
     // FIXME: this was cloned from analysis.  I couldn't figure out
     // how to reuse it.
     fn write_w256(&mut self, w:&w256) -> std::io::Result<()> {
@@ -232,110 +640,358 @@ impl<'a,T:Write> BlockPrinter<'a,T> {
     }
     
     
-    fn print_code(&mut self, code: &Bytecode, state: &BlockState) {
+    fn print_code(&mut self, block: &Block, code: &Bytecode, state: &BlockState, pc: usize) -> std::io::Result<()> {
+        let v = self.var();
+        // Localise memory precondition failures (if applicable)
+        if self.settings.debug && matches!(code,Bytecode::Unit(MLOAD)) {
+            self.print_memory_assert(state)?;
+        }
         //
         match code {
             Bytecode::Assert(uses,s) => {
-                writeln!(self.out,"\t\tassert {s};");
+                let s = substitute_operands(s,uses,&v);
+                writeln!(self.out,"{}assert {s};",self.indent(1))?;
             }            
             Bytecode::Comment(s) => {
-                writeln!(self.out,"\t\t// {s}");
+                self.print_comment(1,s)?;
+            }
+            Bytecode::Havoc(n) => {
+                if !self.settings.no_havoc_comments {
+                    self.print_comment(1,&format!("Havoc {n}"))?;
+                }
             }
             Bytecode::Jump(targets) => {
-                self.print_jump(targets);
+                self.print_jump(block,targets,None)?;
             }
             Bytecode::JumpI(targets) => {
-                self.print_jumpi(targets);
+                // Check whether branch condition is known to be false
+                let never = state.operand(1) == Some(w256::from(0));
+                self.print_jumpi(block,targets,pc + code.length(),never,None)?;
+            }
+            Bytecode::RJump(targets) => {
+                let offset = (targets[0] as isize) - ((pc + code.length()) as isize);
+                self.print_jump(block,targets,Some(offset))?;
+            }
+            Bytecode::RJumpI(targets) => {
+                let offset = (targets[0] as isize) - ((pc + code.length()) as isize);
+                let never = state.operand(0) == Some(w256::from(0));
+                self.print_jumpi(block,targets,pc + code.length(),never,Some(offset))?;
             }
 	    Bytecode::Mask(mask) => {
 		let name = &OPCODES[AND.opcode() as usize];
 		if *mask == 0 || !self.settings.masks {
-		    writeln!(self.out,"\t\tst := {name}(st);");                
+		    self.update(&format!("{name}({v})"))?;
 		} else {
-		    writeln!(self.out,"\t\tst := AndU{mask}(st);");                		    
+		    self.update(&format!("AndU{mask}({v})"))?;
+		}
+		// Help Dafny with the bound on the result (if applicable)
+		if *mask != 0 && *mask < 256 && self.settings.mask_hints {
+		    let v = self.var();
+		    let max = if *mask == 160 { "MAX_U160".to_string() } else { max_value(*mask) };
+		    writeln!(self.out,"{}assert {v}.EXECUTING? ==> {v}.Peek(0) <= {max};",self.indent(1))?;
 		}
 	    }
             Bytecode::Unit(CALL) => {
-                self.print_call();
+                self.print_call()?;
+                self.print_precompile_facts(state)?;
             }            
+            Bytecode::Unit(insn @ (CREATE|CREATE2)) => {
+                let name = &OPCODES[insn.opcode() as usize];
+                self.print_create(name)?;
+            }
+            Bytecode::Unit(SELFDESTRUCT) => {
+                self.print_selfdestruct()?;
+            }
+            Bytecode::Unit(STATICCALL) => {
+                let name = &OPCODES[STATICCALL.opcode() as usize];
+                self.update(&format!("{name}({v})"))?;
+                self.print_precompile_facts(state)?;
+            }
+            Bytecode::Unit(CALLF(cid)) => {
+                self.print_callf(*cid as usize)?;
+            }
+            Bytecode::Unit(RETF) => {
+                // Block ends here, hence the (still executing) state
+                // is returned to the caller.
+                self.print_comment(1,"Return to caller")?;
+            }
             Bytecode::Unit(DUP(n)) => {
-                writeln!(self.out,"\t\tst := Dup(st,{n});");                                     
+                self.update(&format!("Dup({v},{n})"))?;
             }            
             Bytecode::Unit(LOG(n)) => {
-                writeln!(self.out,"\t\tst := LogN(st,{n});");                                     
+                self.update(&format!("LogN({v},{n})"))?;
             }
             Bytecode::Unit(PUSH(bytes)) => {
                 let n = bytes.len();
                 let hex = bytes.to_hex_string();
                 match n {
-                    1 => writeln!(self.out,"\t\tst := Push1(st,{});", hex),
-                    2 => writeln!(self.out,"\t\tst := Push2(st,{});", hex),
-                    3 => writeln!(self.out,"\t\tst := Push3(st,{});", hex),
-                    4 => writeln!(self.out,"\t\tst := Push4(st,{});", hex),                    
+                    1..=32 => self.update(&format!("Push{n}({v},{})", hex))?,
                     _ => {
-                        writeln!(self.out,"\t\tst := PushN(st,{n},{});", hex)
+                        self.update(&format!("PushN({v},{n},{})", hex))?
                     }                    
                 };
             }            
             Bytecode::Unit(SWAP(n)) => {
-                writeln!(self.out,"\t\tst := Swap(st,{n});");
+                self.update(&format!("Swap({v},{n})"))?;
             }            
+            Bytecode::Unit(JUMP) => {
+                // Target(s) unknown, hence the block ends here and the
+                // (still executing) state is returned as is.  Observe
+                // that execution from the target is not modelled.
+                writeln!(self.out,"{}// UNRESOLVED JUMP",self.indent(1))?;
+                self.update(&format!("Jump({v})"))?;
+            }
+            Bytecode::Unit(JUMPI) => {
+                // Target(s) unknown, hence only fall-thru continues
+                let fallthru = pc + code.length();
+                writeln!(self.out,"{}// UNRESOLVED JUMP",self.indent(1))?;
+                self.update(&format!("JumpI({v})"))?;
+                let v = self.var();
+                if self.settings.functional {
+                    writeln!(self.out,"{}if {v}.PC() != {fallthru:#x} then {v} else",self.indent(1))?;
+                } else {
+                    writeln!(self.out,"{}if {v}.PC() != {fallthru:#x} {{ return {v}; }}",self.indent(1))?;
+                }
+            }
+            Bytecode::Unit(DATA(bytes)) => {
+                // Embedded data is not executable
+                self.print_comment(1,&format!("Data ({} bytes): {}",bytes.len(),bytes.to_hex_string()))?;
+            }
             Bytecode::Unit(insn) => {
                 let name = &OPCODES[insn.opcode() as usize];                
-                writeln!(self.out,"\t\tst := {name}(st);");                
+                self.update(&format!("{name}({v})"))?;
+            }
+        };
+        Ok(())
+    }
+
+    /// Print a comment relating a bytecode to its offset within the
+    /// original instruction sequence (if applicable).
+    fn print_pc_comment(&mut self, code: &Bytecode, pc: usize) -> std::io::Result<()> {
+        if !self.settings.pc_comments { return Ok(()); }
+        let mnemonic = match code {
+            Bytecode::Jump(_) => "JUMP".to_string(),
+            Bytecode::JumpI(_) => "JUMPI".to_string(),
+            Bytecode::RJump(_) => "RJUMP".to_string(),
+            Bytecode::RJumpI(_) => "RJUMPI".to_string(),
+            Bytecode::Mask(_) => "AND".to_string(),
+            Bytecode::Unit(insn) => insn.to_string(),
+            _ => { return Ok(()); }
+        };
+        writeln!(self.out,"{}// {pc:#06x}: {mnemonic}",self.indent(1))?;
+        Ok(())
+    }
+
+    /// Print an assertion that memory is large enough for a word read
+    /// at a known (constant) offset.  This is only emitted when the
+    /// word read is covered by prior writes with known contents, since
+    /// only then is memory known to have been expanded that far.
+    fn print_memory_assert(&mut self, state: &BlockState) -> std::io::Result<()> {
+        let v = self.var();
+        let off = match state.stack_item(0) {
+            Some(w) if w.byte_len() <= 4 => w.to::<usize>(),
+            _ => { return Ok(()); }
+        };
+        let words = state.memory_words();
+        let end = off + 32;
+        let mut p = off;
+        while p < end {
+            match words.iter().find(|(o,_)| *o <= p && p < *o + 32) {
+                Some((o,_)) => { p = *o + 32; }
+                None => { return Ok(()); }
             }
+        }
+        writeln!(self.out,"{}assert {v}.MemSize() >= {end:#x};",self.indent(1))?;
+        Ok(())
+    }
+
+    /// Print facts known to hold after a successful call (i.e. one
+    /// which pushes `1`) to a (constant) precompile address, as given
+    /// by the precompile model.
+    fn print_precompile_facts(&mut self, state: &BlockState) -> std::io::Result<()> {
+        let v = self.var();
+        // Address is the second operand of CALL / STATICCALL
+        let address = match state.stack_item(1) {
+            Some(a) if a.byte_len() <= 8 => a.to::<usize>(),
+            _ => { return Ok(()); }
         };
+        let model = match self.settings.precompiles.get(&address) {
+            Some(m) => m,
+            None => { return Ok(()); }
+        };
+        writeln!(self.out,"{}// Precompile {} ({address:#x})",self.indent(1),model.name)?;
+        if let Some(n) = model.returndatasize {
+            writeln!(self.out,"{}assume {{:axiom}} {v}.EXECUTING? && {v}.Peek(0) == 1 ==> |{v}.evm.context.returnData| == {n};",self.indent(1))?;
+        }
+        for f in &model.facts {
+            let f = substitute_state(f,&v);
+            writeln!(self.out,"{}assume {{:axiom}} {v}.EXECUTING? && {v}.Peek(0) == 1 ==> {f};",self.indent(1))?;
+        }
+        Ok(())
     }
 
-    fn print_jump(&mut self, targets: &[usize]) {
-        // Print out assumptions
-        self.print_jump_assumes(targets);
-        // Print out instruction
-        writeln!(self.out,"\t\tst := Jump(st);");
+    /// Print an unconditional jump, where `relative` gives the offset
+    /// for a relative jump.
+    fn print_jump(&mut self, block: &Block, targets: &[usize], relative: Option<isize>) -> std::io::Result<()> {
+        let v = self.var();
+        match relative {
+            None => {
+                // Print out assumptions
+                self.print_jump_assumes(targets)?;
+                // Print out instruction
+                self.update(&format!("Jump({v})"))?;
+            }
+            Some(offset) => {
+                self.update(&format!("RJump({v},{offset})"))?;
+            }
+        }
+        let v = self.var();
         // Manage Control Flow
-        if targets.len() == 1 {
-            writeln!(self.out,"\t\tst := block_{}_{:#06x}(st);", self.id, targets[0]);
+        if self.settings.functional {
+            // Dispatch becomes a conditional expression
+            if let Some((last,rest)) = targets.split_last() {
+                let mut rhs = String::new();
+                for target in rest {
+                    rhs.push_str(&format!("if {v}.PC() == {target:#x} then block_{}_{:#06x}({v}) else ",self.id,block.callee(*target)));
+                }
+                rhs.push_str(&format!("block_{}_{:#06x}({v})",self.id,block.callee(*last)));
+                self.update(&rhs)?;
+            }
+        } else if targets.len() == 1 {
+            writeln!(self.out,"{}{v} := block_{}_{:#06x}({v});",self.indent(1), self.id, block.callee(targets[0]))?;
         } else {
-            writeln!(self.out,"\t\tmatch st.PC() {{");
+            writeln!(self.out,"{}match {v}.PC() {{",self.indent(1))?;
             for target in targets {
-                writeln!(self.out,"\t\t\tcase {target:#x} => {{ st := block_{}_{target:#06x}(st); }}",self.id);
+                writeln!(self.out,"{}case {target:#x} => {{ {v} := block_{}_{:#06x}({v}); }}",self.indent(2),self.id,block.callee(*target))?;
             }
-            writeln!(self.out,"\t}}");
+            writeln!(self.out,"{}}}",self.indent(0))?;
         }
+        Ok(())
     }
 
-    fn print_jumpi(&mut self, targets: &[usize]) {
-        // Print out assumptions
-        self.print_jump_assumes(targets);
-        // Print out instruction
-        writeln!(self.out,"\t\tst := JumpI(st);");        
+    /// Print a conditional jump, where `relative` gives the offset for
+    /// a relative jump.
+    fn print_jumpi(&mut self, block: &Block, targets: &[usize], fallthru: usize, never: bool, relative: Option<isize>) -> std::io::Result<()> {
+        let v = self.var();
+        match relative {
+            None => {
+                // Print out assumptions
+                if !never { self.print_jump_assumes(targets)?; }
+                // Print out instruction
+                self.update(&format!("JumpI({v})"))?;
+            }
+            Some(offset) => {
+                self.update(&format!("RJumpI({v},{offset})"))?;
+            }
+        }
+        let v = self.var();
+        // Targets which coincide with the fall-thru need no dispatch,
+        // since control continues there regardless.
+        let targets : Vec<usize> = targets.iter().copied().filter(|t| *t != fallthru).collect();
         // Manage Control Flow
-        if targets.len() == 1 {
+        if never {
+            self.print_comment(1,"Branch never taken")?;
+        } else if targets.len() == 0 {
+            self.print_comment(1,"Branch target is fall-thru")?;
+        } else if self.settings.functional {
+            // Remainder of block becomes the else branch
+            for target in &targets {
+                writeln!(self.out,"{}if {v}.PC() == {target:#x} then block_{}_{:#06x}({v}) else",self.indent(1),self.id,block.callee(*target))?;
+            }
+        } else if targets.len() == 1 {
             let target = targets[0];
-            writeln!(self.out,"\t\tif st.PC() == {target:#x} {{ st := block_{}_{target:#06x}(st); return st;}}",self.id);
+            writeln!(self.out,"{}if {v}.PC() == {target:#x} {{ {v} := block_{}_{:#06x}({v}); return {v};}}",self.indent(1),self.id,block.callee(target))?;
         } else {
-            writeln!(self.out,"\tmatch st.PC() {{");
-            for target in targets {
-                writeln!(self.out,"\t\tcase {target:#x} => {{ st := block_{}_{target:#06x}(st); return st;}}",self.id);
+            writeln!(self.out,"{}match {v}.PC() {{",self.indent(0))?;
+            for target in &targets {
+                writeln!(self.out,"{}case {target:#x} => {{ {v} := block_{}_{:#06x}({v}); return {v};}}",self.indent(1),self.id,block.callee(*target))?;
             }
-            writeln!(self.out,"\t\tcase _ => {{}}");
-            writeln!(self.out,"\t}}");            
+            writeln!(self.out,"{}case _ => {{}}",self.indent(1))?;
+            writeln!(self.out,"{}}}",self.indent(0))?;            
         }
+        Ok(())
     }
 
-    fn print_jump_assumes(&mut self, targets: &[usize]) {
+    fn print_jump_assumes(&mut self, targets: &[usize]) -> std::io::Result<()> {
+        let v = self.var();
+        // For a fully static jump, check the target is a JUMPDEST
+        // directly against the bytecode.
+        if self.settings.check_jumpdests && targets.len() == 1 {
+            writeln!(self.out,"{}assert BYTECODE_{}[{:#x}] == 0x5b;",self.indent(1),self.id,targets[0])?;
+        }
         for target in targets {
-            writeln!(self.out,"\t\tassume {{:axiom}} st.IsJumpDest({target:#x});");
+            // Targets beginning with a JUMPDEST require this themselves
+            if !self.settings.jumpdest_requires || !self.jumpdests.contains(target) {
+                writeln!(self.out,"{}assume {{:axiom}} {v}.IsJumpDest({target:#x});",self.indent(1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Print a call into the entry block of a given code section (i.e.
+    /// `CALLF`).  The callee returns either on `RETF`, in which case
+    /// execution continues after the call, or once execution halts.
+    fn print_callf(&mut self, cid: usize) -> std::io::Result<()> {
+        let v = self.var();
+        self.update(&format!("block_{cid}_0x0000({v})"))?;
+        let v = self.var();
+        if self.settings.functional {
+            writeln!(self.out,"{}if !{v}.EXECUTING? then {v} else",self.indent(1))?;
+        } else {
+            writeln!(self.out,"{}if !{v}.EXECUTING? {{ return {v}; }}",self.indent(1))?;
+        }
+        Ok(())
+    }
+
+    /// Print a contract creation (i.e. `CREATE` or `CREATE2`).  The
+    /// init code is executed as for an external call.  The address of
+    /// the created contract is chosen arbitrarily (i.e. `:| true`),
+    /// since deriving it requires hashing the sender with its nonce
+    /// (or salt), which is not modelled.  Hence, nothing proved can
+    /// depend upon the address.
+    fn print_create(&mut self, name: &str) -> std::io::Result<()> {
+        let v = self.var();
+        if self.settings.functional {
+            self.update(&format!("match {name}({v}) {{ case CONTINUING(cc) => (var address: u160 :| true; var inner := cc.CreateEnter(1,address,cc.initcode); cc.CreateReturn(if inner.EXECUTING? then external_call(cc.sender,inner) else inner,address)) case s => s }}"))?;
+            return Ok(());
         }
+        writeln!(self.out,"{}var CONTINUING(cc) := {name}({v});",self.indent(1))?;
+        writeln!(self.out,"{}{{",self.indent(1))?;
+        writeln!(self.out,"{}var address: u160 :| true; // arbitrary (derivation not modelled)",self.indent(2))?;
+        writeln!(self.out,"{}var inner := cc.CreateEnter(1,address,cc.initcode);",self.indent(2))?;
+        writeln!(self.out,"{}if inner.EXECUTING? {{ inner := external_call(cc.sender,inner); }}",self.indent(2))?;
+        writeln!(self.out,"{}{v} := cc.CreateReturn(inner,address);",self.indent(2))?;
+        writeln!(self.out,"{}}}",self.indent(1))?;
+        Ok(())
     }
 
-    fn print_call(&mut self) {
-        writeln!(self.out,"\t\tvar CONTINUING(cc) := Call(st);");
-        writeln!(self.out,"\t\t{{");
-        writeln!(self.out,"\t\t\tvar inner := cc.CallEnter(1);");
-        writeln!(self.out,"\t\t\tif inner.EXECUTING? {{ inner := external_call(cc.sender,inner); }}");
-        writeln!(self.out,"\t\t\tst := cc.CallReturn(inner);");
-        writeln!(self.out,"\t\t}}");
+    /// Print a `SELFDESTRUCT`, after which execution has terminated
+    /// (i.e. there is no continuation, unlike for a call).
+    fn print_selfdestruct(&mut self) -> std::io::Result<()> {
+        let v = self.var();
+        let name = &OPCODES[SELFDESTRUCT.opcode() as usize];
+        self.update(&format!("{name}({v})"))?;
+        let v = self.var();
+        self.print_comment(1,"Execution terminates")?;
+        if !self.settings.functional {
+            writeln!(self.out,"{}assert !{v}.EXECUTING?;",self.indent(1))?;
+        }
+        Ok(())
+    }
+
+    fn print_call(&mut self) -> std::io::Result<()> {
+        let v = self.var();
+        if self.settings.functional {
+            self.update(&format!("match Call({v}) {{ case CONTINUING(cc) => (var inner := cc.CallEnter(1); cc.CallReturn(if inner.EXECUTING? then external_call(cc.sender,inner) else inner)) case s => s }}"))?;
+            return Ok(());
+        }
+        writeln!(self.out,"{}var CONTINUING(cc) := Call({v});",self.indent(1))?;
+        writeln!(self.out,"{}{{",self.indent(1))?;
+        writeln!(self.out,"{}var inner := cc.CallEnter(1);",self.indent(2))?;
+        writeln!(self.out,"{}if inner.EXECUTING? {{ inner := external_call(cc.sender,inner); }}",self.indent(2))?;
+        writeln!(self.out,"{}{v} := cc.CallReturn(inner);",self.indent(2))?;
+        writeln!(self.out,"{}}}",self.indent(1))?;
+        Ok(())
     }
     
 }
@@ -376,3 +1032,98 @@ fn has_value(st: &AbstractState) -> bool {
     }
     count > 0
 }
+
+/// Substitute placeholders of the form `$n` in a given assertion with
+/// `v.Peek(n)`, for a given state variable `v`.  Every placeholder
+/// must refer to a declared dependency, since otherwise minimisation
+/// may have cleared the corresponding stack item.
+fn substitute_operands(s: &str, deps: &[usize], v: &str) -> String {
+    let mut r = String::new();
+    let mut chars = s.chars().peekable();
+    //
+    while let Some(c) = chars.next() {
+        if c == '$' && matches!(chars.peek(),Some(d) if d.is_ascii_digit()) {
+            let mut n = 0;
+            while let Some(d) = chars.peek().and_then(|d| d.to_digit(10)) {
+                n = (n * 10) + (d as usize);
+                chars.next();
+            }
+            assert!(deps.contains(&n),"placeholder ${n} not a declared dependency");
+            r.push_str(&format!("{v}.Peek({n})"));
+        } else {
+            r.push(c);
+        }
+    }
+    r
+}
+
+/// Substitute a given state variable for each occurrence of the
+/// identifier `st` within a given expression.
+fn substitute_state(s: &str, v: &str) -> String {
+    let mut r = String::new();
+    let mut ident = String::new();
+    //
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '\'' {
+            ident.push(c);
+        } else {
+            r.push_str(if ident == "st" { v } else { &ident });
+            ident.clear();
+            r.push(c);
+        }
+    }
+    r.push_str(if ident == "st" { v } else { &ident });
+    r
+}
+
+/// Determine the requirement on the operand stack height for a given
+/// (sorted) set of heights.  Runs of three or more heights are
+/// coalesced into ranges.
+fn height_clauses(heights: &[usize]) -> String {
+    let mut clauses = Vec::new();
+    let mut singles = Vec::new();
+    let mut i = 0;
+    while i < heights.len() {
+        let mut j = i;
+        while j + 1 < heights.len() && heights[j+1] == heights[j] + 1 { j += 1; }
+        if j - i >= 2 {
+            clauses.push(format!("(st'.Operands() >= {} && st'.Operands() <= {})",heights[i],heights[j]));
+        } else {
+            singles.extend(heights[i..=j].iter().map(|h| h.to_string()));
+        }
+        i = j + 1;
+    }
+    if singles.len() > 0 {
+        clauses.push(format!("st'.Operands() in {{{}}}",singles.join(",")));
+    }
+    clauses.join(" || ")
+}
+
+/// Determine the maximum value (in hex) which fits within a given
+/// number of bits.
+fn max_value(width: usize) -> String {
+    let lead = match width % 4 { 0 => "", 1 => "1", 2 => "3", _ => "7" };
+    format!("0x{lead}{}","f".repeat(width / 4))
+}
+
+// ===================================================================
+// Tests
+// ===================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_height_clauses_01() {
+        // Runs are coalesced into ranges
+        assert_eq!(height_clauses(&[2,3,4,7,8]),"(st'.Operands() >= 2 && st'.Operands() <= 4) || st'.Operands() in {7,8}");
+    }
+
+    #[test]
+    fn test_height_clauses_02() {
+        // Short runs are enumerated
+        assert_eq!(height_clauses(&[1,2,5]),"st'.Operands() in {1,2,5}");
+        assert_eq!(height_clauses(&[1,2,3,5,6,7]),"(st'.Operands() >= 1 && st'.Operands() <= 3) || (st'.Operands() >= 5 && st'.Operands() <= 7)");
+    }
+}
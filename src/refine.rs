@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::fmt;
+use evmil::bytecode::Instruction::*;
+use evmil::util::w256;
+use crate::analysis::write_w256;
+use crate::block::{Block,Bytecode,insn_produces};
+
+// =============================================================================
+// Facts
+// =============================================================================
+
+/// Identifies something about the incoming state which a fact
+/// constrains.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Subject {
+    /// The value transferred with this call (i.e. `CALLVALUE`).
+    CallValue,
+    /// The size of the call data (i.e. `CALLDATASIZE`).
+    CallDataSize,
+    /// The ith item on the stack (e.g. a function selector compared
+    /// by the dispatcher).
+    Operand(usize)
+}
+
+/// Comparators which can be used in a fact.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Cmp { Eq, Neq, Lt, LtEq, Gt, GtEq }
+
+impl Cmp {
+    /// Determine the comparator which holds when this does not.
+    fn negate(self) -> Self {
+        match self {
+            Cmp::Eq => Cmp::Neq,
+            Cmp::Neq => Cmp::Eq,
+            Cmp::Lt => Cmp::GtEq,
+            Cmp::LtEq => Cmp::Gt,
+            Cmp::Gt => Cmp::LtEq,
+            Cmp::GtEq => Cmp::Lt
+        }
+    }
+    /// Determine the comparator obtained by swapping operands.
+    fn flip(self) -> Self {
+        match self {
+            Cmp::Lt => Cmp::Gt,
+            Cmp::LtEq => Cmp::GtEq,
+            Cmp::Gt => Cmp::Lt,
+            Cmp::GtEq => Cmp::LtEq,
+            c => c
+        }
+    }
+}
+
+impl fmt::Display for Cmp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Cmp::Eq => "==",
+            Cmp::Neq => "!=",
+            Cmp::Lt => "<",
+            Cmp::LtEq => "<=",
+            Cmp::Gt => ">",
+            Cmp::GtEq => ">="
+        };
+        write!(f,"{s}")
+    }
+}
+
+/// A fact learned along a conditional branch which is known to hold
+/// on entry to a given block.  For example, after the prologue
+/// `CALLVALUE; ISZERO; PUSH dest; JUMPI` we know the call value is
+/// zero on entry to `dest`.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Fact {
+    subject: Subject,
+    cmp: Cmp,
+    value: w256
+}
+
+impl fmt::Display for Fact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.subject {
+            Subject::CallValue => { write!(f,"st'.evm.context.CallValue()")?; }
+            Subject::CallDataSize => { write!(f,"st'.evm.context.CallDataSize()")?; }
+            Subject::Operand(i) => { write!(f,"st'.Peek({i})")?; }
+        }
+        write!(f," {} ",self.cmp)?;
+        write_w256(f,&self.value)
+    }
+}
+
+// =============================================================================
+// Symbolic Terms
+// =============================================================================
+
+/// A symbolic description of a value on the stack, as determined by
+/// walking the bytecodes of a block.
+#[derive(Clone,Debug,PartialEq)]
+enum Term {
+    Const(w256),
+    CallValue,
+    CallDataSize,
+    /// The ith item on the stack when the block was entered.
+    Entry(usize),
+    IsZero(Box<Term>),
+    Lt(Box<Term>,Box<Term>),
+    Gt(Box<Term>,Box<Term>),
+    Eq(Box<Term>,Box<Term>),
+    Unknown
+}
+
+impl Term {
+    /// Convert this term into a subject (where possible).
+    fn as_subject(&self) -> Option<Subject> {
+        match self {
+            Term::CallValue => Some(Subject::CallValue),
+            Term::CallDataSize => Some(Subject::CallDataSize),
+            // Relative to the entry of the enclosing block (see
+            // `SymbolicStack::relocate`).
+            Term::Entry(i) => Some(Subject::Operand(*i)),
+            _ => None
+        }
+    }
+
+    /// Determine the facts which hold when this term (as a branch
+    /// condition) is non-zero (`holds == true`) or zero (`holds ==
+    /// false`).
+    fn facts(&self, holds: bool) -> Vec<Fact> {
+        match self {
+            Term::IsZero(t) => t.facts(!holds),
+            Term::Eq(l,r) => Self::compare(l,Cmp::Eq,r,holds),
+            Term::Lt(l,r) => Self::compare(l,Cmp::Lt,r,holds),
+            Term::Gt(l,r) => Self::compare(l,Cmp::Gt,r,holds),
+            t => {
+                let zero = Term::Const(w256::from(0));
+                Self::compare(t,Cmp::Neq,&zero,holds)
+            }
+        }
+    }
+
+    fn compare(lhs: &Term, cmp: Cmp, rhs: &Term, holds: bool) -> Vec<Fact> {
+        let cmp = if holds { cmp } else { cmp.negate() };
+        match (lhs.as_subject(),rhs,lhs,rhs.as_subject()) {
+            (Some(subject),Term::Const(v),_,_) => {
+                vec![Fact{subject,cmp,value:*v}]
+            }
+            (_,_,Term::Const(v),Some(subject)) => {
+                vec![Fact{subject,cmp:cmp.flip(),value:*v}]
+            }
+            (_,_,_,_) => Vec::new()
+        }
+    }
+}
+
+/// A symbolic stack, where items beneath those pushed within the
+/// block are materialised as `Term::Entry` on demand.
+struct SymbolicStack {
+    items: Vec<Term>,
+    entries: usize
+}
+
+impl SymbolicStack {
+    fn new() -> Self {
+        Self{items: Vec::new(), entries: 0}
+    }
+
+    /// Ensure at least `n` items are materialised on the stack.
+    fn ensure(&mut self, n: usize) {
+        while self.items.len() < n {
+            self.items.insert(0,Term::Entry(self.entries));
+            self.entries += 1;
+        }
+    }
+
+    fn push(&mut self, t: Term) {
+        self.items.push(t);
+    }
+
+    fn pop(&mut self) -> Term {
+        self.ensure(1);
+        self.items.pop().unwrap()
+    }
+
+    fn dup(&mut self, n: usize) {
+        self.ensure(n);
+        let t = self.items[self.items.len() - n].clone();
+        self.items.push(t);
+    }
+
+    fn swap(&mut self, n: usize) {
+        self.ensure(n+1);
+        let m = self.items.len() - 1;
+        self.items.swap(m, m - n);
+    }
+
+    /// Relocate a fact about an item on the stack when the enclosing
+    /// block was entered, such that it instead refers to where that
+    /// item is now.  This fails if the item is no longer on the
+    /// stack.
+    fn relocate(&self, fact: Fact) -> Option<Fact> {
+        match fact.subject {
+            Subject::Operand(k) => {
+                let n = self.items.len();
+                match self.items.iter().rposition(|t| *t == Term::Entry(k)) {
+                    Some(p) => Some(Fact{subject: Subject::Operand(n - 1 - p),..fact}),
+                    // Items not yet materialised are unchanged
+                    None if k >= self.entries => Some(Fact{subject: Subject::Operand(n + k - self.entries),..fact}),
+                    None => None
+                }
+            }
+            _ => Some(fact)
+        }
+    }
+}
+
+// =============================================================================
+// Refinement
+// =============================================================================
+
+/// Determine facts learned along conditional branches and attach them
+/// to the successor blocks they hold for.  A fact is only attached to
+/// a block which has exactly one incoming edge, since otherwise it
+/// need not hold on entry.
+pub fn refine_branches(blocks: &mut [Block]) {
+    let indegree = determine_indegrees(blocks);
+    let mut facts : HashMap<usize,Vec<Fact>> = HashMap::new();
+    //
+    for blk in blocks.iter() {
+        if blk.is_unreachable() { continue; }
+        let mut stack = SymbolicStack::new();
+        let n = blk.bytecodes().len();
+        for (i,bc) in blk.iter().enumerate() {
+            match bc {
+                Bytecode::JumpI(targets)|Bytecode::RJumpI(targets) => {
+                    // Relative jumps have no target operand
+                    if matches!(bc,Bytecode::JumpI(_)) { let _ = stack.pop(); }
+                    let cond = stack.pop();
+                    let taken : Vec<Fact> = cond.facts(true).into_iter().filter_map(|f| stack.relocate(f)).collect();
+                    for t in targets {
+                        facts.entry(*t).or_default().extend(taken.iter().cloned());
+                    }
+                    // Fall-thru is only a distinct block if the
+                    // branch ends this block.
+                    if let (true,Some(pc)) = (i+1 == n, blk.next()) {
+                        let untaken = cond.facts(false).into_iter().filter_map(|f| stack.relocate(f));
+                        facts.entry(pc).or_default().extend(untaken);
+                    }
+                }
+                _ => transfer(bc,&mut stack)
+            }
+        }
+    }
+    // Attach facts to blocks with a single predecessor
+    for blk in blocks.iter_mut() {
+        let pc = blk.pc();
+        if pc != 0 && indegree.get(&pc) == Some(&1) {
+            if let Some(fs) = facts.remove(&pc) { blk.set_facts(fs); }
+        }
+    }
+}
+
+/// Apply the effect of a given bytecode to a symbolic stack.
+fn transfer(bytecode: &Bytecode, stack: &mut SymbolicStack) {
+    match bytecode {
+        Bytecode::Comment(_)|Bytecode::Havoc(_)|Bytecode::Assert(_,_)|Bytecode::RJump(_) => {}
+        Bytecode::Jump(_)|Bytecode::RJumpI(_) => { stack.pop(); }
+        Bytecode::JumpI(_) => { stack.pop(); stack.pop(); }
+        Bytecode::Mask(_) => {
+            stack.pop();
+            stack.pop();
+            stack.push(Term::Unknown);
+        }
+        Bytecode::Unit(PUSH0) => stack.push(Term::Const(w256::from(0))),
+        Bytecode::Unit(PUSH(bytes)) => stack.push(Term::Const(w256::from_be_slice(bytes))),
+        Bytecode::Unit(DUP(n)) => stack.dup(*n as usize),
+        Bytecode::Unit(SWAP(n)) => stack.swap(*n as usize),
+        Bytecode::Unit(CALLVALUE) => stack.push(Term::CallValue),
+        Bytecode::Unit(CALLDATASIZE) => stack.push(Term::CallDataSize),
+        Bytecode::Unit(ISZERO) => {
+            let t = stack.pop();
+            stack.push(Term::IsZero(Box::new(t)));
+        }
+        Bytecode::Unit(LT|GT|EQ) => {
+            let l = Box::new(stack.pop());
+            let r = Box::new(stack.pop());
+            let t = match bytecode {
+                Bytecode::Unit(LT) => Term::Lt(l,r),
+                Bytecode::Unit(GT) => Term::Gt(l,r),
+                _ => Term::Eq(l,r)
+            };
+            stack.push(t);
+        }
+        Bytecode::Unit(insn) => {
+            for _ in 0..insn.operands() { stack.pop(); }
+            for _ in 0..insn_produces(insn) { stack.push(Term::Unknown); }
+        }
+    }
+}
+
+/// Determine the number of incoming edges for each block.
+fn determine_indegrees(blocks: &[Block]) -> HashMap<usize,usize> {
+    let mut indegree = HashMap::new();
+    //
+    for blk in blocks {
+        for bc in blk.iter() {
+            match bc {
+                Bytecode::Jump(targets)|Bytecode::JumpI(targets)|Bytecode::RJump(targets)|Bytecode::RJumpI(targets) => {
+                    for t in targets { *indegree.entry(*t).or_insert(0) += 1; }
+                }
+                _ => {}
+            }
+        }
+        if let Some(pc) = blk.next() {
+            *indegree.entry(pc).or_insert(0) += 1;
+        }
+    }
+    //
+    indegree
+}
+
+// =============================================================================
+// Stack Values
+// =============================================================================
+
+/// Describes what is known about a given stack item, beyond its
+/// (possibly) concrete value.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum StackValue {
+    /// Nothing is known about this item.
+    Unknown,
+    /// This item has a known width `n`, since it was produced by
+    /// masking (e.g. `AND 0xff..ff`) and, hence, fits in `n` bits.
+    Width(usize),
+    /// This item holds the current value of the free memory pointer
+    /// (i.e. it was read from `0x40`, and that has not since been
+    /// written) plus a constant offset.
+    FreeMemPtr(usize)
+}
+
+impl StackValue {
+    /// Join two stack values, retaining only what is known in both.
+    fn join(&self, other: &StackValue) -> StackValue {
+        match (self,other) {
+            (StackValue::Width(v),StackValue::Width(w)) => StackValue::Width(*v.max(w)),
+            (StackValue::FreeMemPtr(k),StackValue::FreeMemPtr(l)) if k == l => StackValue::FreeMemPtr(*k),
+            (_,_) => StackValue::Unknown
+        }
+    }
+}
+
+/// Describes what is known about stack items, indexed from the top
+/// of the stack (i.e. as for `Peek`).
+pub type StackValues = Vec<StackValue>;
+
+/// Determine what is known about stack items on entry to each block.
+/// This is a forward dataflow analysis over the blocks, where values
+/// are joined at block boundaries.
+pub fn determine_stack_values(blocks: &mut [Block]) {
+    let offsets : HashMap<usize,usize> = blocks.iter().enumerate().map(|(i,b)| (b.pc(),i)).collect();
+    let mut entries : Vec<Option<StackValues>> = vec![None; blocks.len()];
+    let mut worklist = Vec::new();
+    // Seed the entry block
+    if let Some(i) = offsets.get(&0) {
+        entries[*i] = Some(Vec::new());
+        worklist.push(*i);
+    }
+    //
+    while let Some(i) = worklist.pop() {
+        let blk = &blocks[i];
+        if blk.is_unreachable() { continue; }
+        // Determine outgoing values for each successor
+        let mut stack : Vec<StackValue> = entries[i].clone().unwrap().into_iter().rev().collect();
+        let mut outgoing = Vec::new();
+        for (j,bc) in blk.iter().enumerate() {
+            match bc {
+                Bytecode::Jump(targets)|Bytecode::JumpI(targets)|Bytecode::RJump(targets)|Bytecode::RJumpI(targets) => {
+                    let n = match bc {
+                        Bytecode::RJump(_) => 0,
+                        Bytecode::Jump(_)|Bytecode::RJumpI(_) => 1,
+                        _ => 2
+                    };
+                    for _ in 0..n { stack.pop(); }
+                    for t in targets { outgoing.push((*t,stack.clone())); }
+                }
+                _ => transfer_values(blk,j,&mut stack)
+            }
+        }
+        if let Some(pc) = blk.next() { outgoing.push((pc,stack)); }
+        // Propagate into successors
+        for (pc,stack) in outgoing {
+            let j = match offsets.get(&pc) { Some(j) => *j, None => { continue; } };
+            let vs : StackValues = stack.into_iter().rev().collect();
+            let nvs = match &entries[j] {
+                None => vs,
+                Some(old) => join_values(old,&vs)
+            };
+            if entries[j].as_ref() != Some(&nvs) {
+                entries[j] = Some(nvs);
+                worklist.push(j);
+            }
+        }
+    }
+    // Attach values to blocks
+    for (blk,vs) in blocks.iter_mut().zip(entries) {
+        blk.set_stack_values(vs.unwrap_or_default());
+    }
+}
+
+/// Join two sets of stack values, retaining only what is known in
+/// both.
+fn join_values(lhs: &StackValues, rhs: &StackValues) -> StackValues {
+    lhs.iter().zip(rhs).map(|(l,r)| l.join(r)).collect()
+}
+
+/// Apply the effect of the jth bytecode in a given block to a stack of
+/// values (where the top of the stack is the last item).
+fn transfer_values(blk: &Block, j: usize, stack: &mut Vec<StackValue>) {
+    let bytecode = &blk.bytecodes()[j];
+    match bytecode {
+        Bytecode::Comment(_)|Bytecode::Havoc(_)|Bytecode::Assert(_,_) => {}
+        Bytecode::Jump(_)|Bytecode::JumpI(_)|Bytecode::RJump(_)|Bytecode::RJumpI(_) => unreachable!(),
+        Bytecode::Mask(n) => {
+            stack.pop();
+            stack.pop();
+            stack.push(StackValue::Width(*n));
+        }
+        Bytecode::Unit(DUP(n)) => {
+            let n = *n as usize;
+            while stack.len() < n { stack.insert(0,StackValue::Unknown); }
+            stack.push(stack[stack.len() - n]);
+        }
+        Bytecode::Unit(SWAP(n)) => {
+            let n = *n as usize;
+            while stack.len() <= n { stack.insert(0,StackValue::Unknown); }
+            let m = stack.len() - 1;
+            stack.swap(m, m - n);
+        }
+        Bytecode::Unit(_) if blk.is_fmp_read(j) => {
+            stack.pop();
+            stack.push(StackValue::FreeMemPtr(0));
+        }
+        Bytecode::Unit(ADD) => {
+            let lhs = stack.pop();
+            let rhs = stack.pop();
+            // Adding a constant to an offset of the free memory
+            // pointer gives another such offset.
+            let v = match (lhs,rhs,blk.constant_operand(j,0),blk.constant_operand(j,1)) {
+                (Some(StackValue::FreeMemPtr(k)),_,_,Some(c)) => k.checked_add(c),
+                (_,Some(StackValue::FreeMemPtr(k)),Some(c),_) => k.checked_add(c),
+                (_,_,_,_) => None
+            };
+            stack.push(v.map_or(StackValue::Unknown,StackValue::FreeMemPtr));
+        }
+        Bytecode::Unit(insn) => {
+            for _ in 0..insn.operands() { stack.pop(); }
+            for _ in 0..insn_produces(insn) { stack.push(StackValue::Unknown); }
+        }
+    }
+    // Any write to the free memory pointer invalidates items holding
+    // its previous value.
+    if blk.writes_fmp(j) {
+        for v in stack.iter_mut() {
+            if matches!(v,StackValue::FreeMemPtr(_)) { *v = StackValue::Unknown; }
+        }
+    }
+}